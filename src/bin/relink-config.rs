@@ -0,0 +1,262 @@
+#[allow(dead_code)]
+#[path = "../config.rs"]
+mod config;
+
+use config::*;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+const USAGE: &str = "\
+Usage: relink-config [CONFIG] [OPTIONS]
+
+Checks a redirect_config.toml and prints the values the clients would use.
+Without CONFIG, redirect_config.toml in the working directory is used.
+
+Options:
+  --process <NAME>    Host process name used to pick a [profile]
+  --device <NAME>     Endpoint ID or friendly name used to pick a [device] section
+  --client <INDEX>    Client index used to pick a [<flow>.client.<INDEX>] section [default: 0]
+  --samplerate <HZ>   Engine samplerate [default: 48000]
+  --fundamental <N>   Fundamental period in frames [default: 48]
+  --min <N>           Minimum period in frames [default: 48]
+  --max <N>           Maximum period in frames [default: 480]
+  --buffer <N>        Engine buffer size in frames [default: 1056]
+  --packet <N>        Capture packet in frames, the period the app passes to
+                      InitializeSharedAudioStream or the default device period [default: --max]
+  -h, --help          Print this help";
+
+struct Options {
+    path: PathBuf,
+    process: Option<Box<str>>,
+    device: Option<String>,
+    client: u16,
+    samplerate: u32,
+    fundamental: u32,
+    period: (u32, u32),
+    buffer: u32,
+    packet: Option<u32>,
+}
+impl Options {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Option<Self>, String> {
+        let mut options = Self {
+            path: PathBuf::from("redirect_config.toml"),
+            process: None,
+            device: None,
+            client: 0,
+            samplerate: 48000,
+            fundamental: 48,
+            period: (48, 480),
+            buffer: 1056,
+            packet: None,
+        };
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| format!("missing value for {arg}"))
+            };
+            let number = |value: String| {
+                value
+                    .parse::<u32>()
+                    .ok()
+                    .filter(|&n| n != 0)
+                    .ok_or_else(|| format!("invalid value `{value}` for {arg}"))
+            };
+            match arg.as_str() {
+                "-h" | "--help" => return Ok(None),
+                "--process" => options.process = Some(value()?.into()),
+                "--device" => options.device = Some(value()?),
+                "--client" => {
+                    let value = value()?;
+                    options.client = value
+                        .parse()
+                        .map_err(|_| format!("invalid value `{value}` for {arg}"))?
+                }
+                "--samplerate" => options.samplerate = number(value()?)?,
+                "--fundamental" => options.fundamental = number(value()?)?,
+                "--min" => options.period.0 = number(value()?)?,
+                "--max" => options.period.1 = number(value()?)?,
+                "--buffer" => options.buffer = number(value()?)?,
+                "--packet" => options.packet = Some(number(value()?)?),
+                _ if arg.starts_with('-') => return Err(format!("unknown option {arg}")),
+                _ => options.path = arg.into(),
+            }
+        }
+        if options.period.0 > options.period.1 {
+            return Err("--min is larger than --max".into());
+        }
+        Ok(Some(options))
+    }
+}
+
+fn ms(frames: u32, samplerate: u32) -> f64 {
+    frames as f64 * 1000.0 / samplerate as f64
+}
+
+fn print_flow(config: &RedirectConfig, dataflow: DeviceDataFlow, options: &Options) {
+    let device = options
+        .device
+        .as_ref()
+        .and_then(|name| config.device_overlay(&[name]));
+    let client = config.client_overlay(dataflow, options.client);
+    let overlays = device
+        .map(|(_, overlay)| overlay)
+        .into_iter()
+        .chain(client)
+        .collect::<Vec<_>>();
+    let client_config = config.client_config(dataflow, &overlays);
+    println!("[{dataflow}] mode = {}", client_config.mode);
+    if let Some((pattern, _)) = device {
+        println!("  using device config: {pattern}");
+    }
+    if client.is_some() {
+        println!(
+            "  using client config: {dataflow}.client.{}",
+            options.client
+        );
+    }
+    let info = Shared3Info::new(
+        &client_config,
+        options.samplerate,
+        options.fundamental,
+        options.period,
+    );
+    let samplerate = info.samplerate;
+    println!(
+        "  current_period: {} frames ({:.3}ms)",
+        info.current_period,
+        ms(info.current_period, samplerate)
+    );
+    for (key, rate) in &info.fallbacks {
+        println!("  {key}: {samplerate} not configured, using {rate}");
+    }
+    let buffer = info.target_buf_len().map_or(options.buffer, |len| {
+        len.clamp(info.current_period, options.buffer)
+    });
+    println!(
+        "  normal:  reported buffer {buffer} frames ({:.3}ms)",
+        ms(buffer, samplerate)
+    );
+    match info.compat_buf_len() {
+        Some(hns) => print!(
+            "  compat:  inner duration {hns} * 100ns ({:.3}ms)",
+            hns as f64 / 10000.0
+        ),
+        None => print!("  compat:  inner duration 0 (Windows minimum)"),
+    }
+    println!(", initial fill {buffer} frames");
+    if dataflow == DeviceDataFlow::Playback {
+        match client_config.fade_frames(samplerate) {
+            0 => println!("  fade:    disabled"),
+            len => println!("  fade:    {len} frames ({:.3}ms)", ms(len, samplerate)),
+        }
+    }
+    let ring = info.ring_buf_len().unwrap_or(info.current_period * 10);
+    match dataflow {
+        DeviceDataFlow::Playback => {
+            println!(
+                "  ringbuf: ring length {ring} frames ({:.3}ms), reported period {} * 100ns",
+                ms(ring, samplerate),
+                calculate_period(samplerate, ring)
+            );
+            if let Some(target) = info.ring_target() {
+                let target = target.min(ring);
+                println!(
+                    "  ringbuf: adaptive fill target {target} frames ({:.3}ms)",
+                    ms(target, samplerate)
+                )
+            }
+            let mark = client_config.engine_mark(&info);
+            match client_config.ring_engine_fill {
+                EngineFill::Fill => println!("  ringbuf: engine fill {}", EngineFill::Fill),
+                fill => println!(
+                    "  ringbuf: engine fill {fill}, mark {mark} frames ({:.3}ms)",
+                    ms(mark, samplerate)
+                ),
+            }
+            match client_config.start_threshold(samplerate).min(ring) {
+                0 => {}
+                threshold => println!(
+                    "  ringbuf: start threshold {threshold} frames ({:.3}ms)",
+                    ms(threshold, samplerate)
+                ),
+            }
+            match client_config.ring_stall_periods {
+                0 => println!("  ringbuf: on underrun {}", client_config.ring_underrun),
+                periods => println!(
+                    "  ringbuf: on underrun {}, stall after {periods} periods",
+                    client_config.ring_underrun
+                ),
+            }
+        }
+        DeviceDataFlow::Capture => {
+            let packet = options.packet.unwrap_or(options.period.1);
+            let ring = ring.max(packet * 2);
+            println!(
+                "  ringbuf: ring length {ring} frames ({:.3}ms), packets of {packet} frames ({:.3}ms)",
+                ms(ring, samplerate),
+                ms(packet, samplerate)
+            )
+        }
+    }
+    println!("  ringbuf: on overflow {}", client_config.ring_overflow);
+}
+
+fn main() -> ExitCode {
+    let options = match Options::parse(std::env::args().skip(1)) {
+        Ok(Some(options)) => options,
+        Ok(None) => {
+            println!("{USAGE}");
+            return ExitCode::SUCCESS;
+        }
+        Err(e) => {
+            eprintln!("error: {e}\n\n{USAGE}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let local = std::fs::read_to_string(&options.path)
+        .ok()
+        .map(|str| (Box::<Path>::from(options.path.as_path()), str));
+    let config = RedirectConfig::load_with(
+        local,
+        vec![options.path.as_path().into()],
+        options.process.clone(),
+    );
+    match &config.source {
+        ConfigSource::Success(path) => {
+            println!("Loaded {}", path.display());
+            for layer in config.layers.iter().filter(|layer| *layer != path) {
+                println!("Layered under it: {}", layer.display());
+            }
+        }
+        ConfigSource::NoParse(e) => {
+            eprintln!("error: {e}");
+            return ExitCode::FAILURE;
+        }
+        ConfigSource::NoFile => println!("No config file found, using default values"),
+    }
+    if let Some(profile) = &config.profile {
+        println!("Using profile: {profile}");
+    }
+    for warning in &config.warnings {
+        println!("warning: {warning}");
+    }
+    println!();
+    for (key, value, origin) in config.effective_values() {
+        println!("{key} = {value} ({origin})");
+    }
+    println!(
+        "\nDevice: {}Hz, fundamental {}, period {}..{}, buffer {}",
+        options.samplerate, options.fundamental, options.period.0, options.period.1, options.buffer
+    );
+    if !config.rules.is_empty() {
+        println!(
+            "{} stream rules are evaluated on Initialize and not applied here",
+            config.rules.len()
+        );
+    }
+    for dataflow in [DeviceDataFlow::Playback, DeviceDataFlow::Capture] {
+        println!();
+        print_flow(&config, dataflow, &options);
+    }
+    ExitCode::SUCCESS
+}
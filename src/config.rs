@@ -0,0 +1,1998 @@
+use log::*;
+use serde::*;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use toml::{Table, Value};
+
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Eq, Clone, Copy)]
+pub(crate) enum ConfigLogLevel {
+    Trace,
+    Debug,
+    #[default]
+    Info,
+    Warn,
+    Error,
+    Never,
+}
+
+impl From<ConfigLogLevel> for LevelFilter {
+    fn from(value: ConfigLogLevel) -> Self {
+        match value {
+            ConfigLogLevel::Trace => Self::Trace,
+            ConfigLogLevel::Debug => Self::Debug,
+            ConfigLogLevel::Info => Self::Info,
+            ConfigLogLevel::Warn => Self::Warn,
+            ConfigLogLevel::Error => Self::Error,
+            ConfigLogLevel::Never => Self::Off,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) enum ConfigSource {
+    Success(Box<Path>),
+    NoParse(ConfigError),
+    #[default]
+    NoFile,
+}
+
+#[derive(Debug)]
+pub(crate) struct ConfigError {
+    message: Box<str>,
+    position: Option<(usize, usize)>,
+    file: Option<Box<Path>>,
+}
+impl ConfigError {
+    fn in_file(self, path: &Path) -> Self {
+        Self {
+            file: Some(path.into()),
+            ..self
+        }
+    }
+    fn new(error: toml::de::Error, str: &str) -> Self {
+        let position = error.span().map(|span| {
+            let before = &str[..span.start.min(str.len())];
+            let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+            (
+                before.matches('\n').count() + 1,
+                before[line_start..].chars().count() + 1,
+            )
+        });
+        Self {
+            position,
+            ..error.into()
+        }
+    }
+}
+impl From<toml::de::Error> for ConfigError {
+    fn from(error: toml::de::Error) -> Self {
+        Self {
+            message: error.message().trim_end().into(),
+            position: None,
+            file: None,
+        }
+    }
+}
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{}, ", file.display())?;
+        }
+        match self.position {
+            Some((line, column)) => write!(f, "line {line}, column {column}: {}", self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum ConfigWarning {
+    UnknownKey(Box<str>),
+    DroppedSection(Box<str>, ConfigError),
+    MissingInclude(Box<Path>, Box<str>),
+    RepeatedInclude(Box<Path>),
+    LegacyKey {
+        file: Box<Path>,
+        section: Box<str>,
+        old: Box<str>,
+        new: Option<Box<str>>,
+    },
+    Unversioned(Box<Path>),
+    NewerVersion(Box<Path>, i64),
+}
+impl std::fmt::Display for ConfigWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnknownKey(key) => write!(f, "Unknown key `{key}` is ignored"),
+            Self::DroppedSection(key, e) => {
+                write!(f, "Dropped `{key}` and using default values, error: {e}")
+            }
+            Self::MissingInclude(path, e) => {
+                write!(
+                    f,
+                    "Unable to read included config {}, error: {e}",
+                    path.display()
+                )
+            }
+            Self::RepeatedInclude(path) => {
+                write!(f, "Config {} is already included, skipping", path.display())
+            }
+            Self::LegacyKey {
+                file,
+                section,
+                old,
+                new: Some(new),
+            } => write!(
+                f,
+                "{}: `{old}` in [{section}] is deprecated, replace it with `{new}`",
+                file.display()
+            ),
+            Self::LegacyKey {
+                file, section, old, ..
+            } => write!(
+                f,
+                "{}: `{old}` in [{section}] is no longer supported, remove it",
+                file.display()
+            ),
+            Self::Unversioned(file) => write!(
+                f,
+                "{} uses a legacy layout, add `config_version = {CONFIG_VERSION}` after updating it",
+                file.display()
+            ),
+            Self::NewerVersion(file, version) => write!(
+                f,
+                "{} is written for config version {version}, newer than {CONFIG_VERSION}, some keys may be ignored",
+                file.display()
+            ),
+        }
+    }
+}
+
+pub(crate) const CONFIG_NAME: &str = "redirect_config.toml";
+const CONFIG_ENV: &str = "WASAPI_RELINK_CONFIG";
+const ENV_PREFIX: &str = "WASAPI_RELINK_";
+pub(crate) const GENERATE_ENV: &str = "WASAPI_RELINK_GENERATE_CONFIG";
+const CONFIG_VERSION: i64 = 2;
+const USER_CONFIG_DIR: &str = "wasapi_relink";
+const DEFAULT_ORIGIN: &str = "default";
+
+#[derive(Debug)]
+pub(crate) struct ConfigFile {
+    path: Box<Path>,
+    str: String,
+    table: Table,
+}
+impl ConfigFile {
+    fn read(
+        path: &Path,
+        str: String,
+        files: &mut Vec<Self>,
+        seen: &mut Vec<Box<Path>>,
+        warnings: &mut Vec<ConfigWarning>,
+    ) -> Result<(), ConfigError> {
+        seen.push(canonical(path));
+        let mut table =
+            toml::from_str::<Table>(&str).map_err(|e| ConfigError::new(e, &str).in_file(path))?;
+        migrate(&mut table, path, warnings);
+        if let Some(include) = table.remove("include") {
+            let include = include
+                .try_into::<Vec<PathBuf>>()
+                .map_err(|e| ConfigError::from(e).in_file(path))?;
+            for include in include {
+                let include = path
+                    .parent()
+                    .map_or(include.clone(), |dir| dir.join(&include));
+                if seen.contains(&canonical(&include)) {
+                    warnings.push(ConfigWarning::RepeatedInclude(include.into()));
+                    continue;
+                }
+                match std::fs::read_to_string(&include) {
+                    Ok(str) => Self::read(&include, str, files, seen, warnings)?,
+                    Err(e) => {
+                        seen.push(canonical(&include));
+                        warnings.push(ConfigWarning::MissingInclude(
+                            include.into(),
+                            e.to_string().into(),
+                        ))
+                    }
+                }
+            }
+        }
+        files.push(Self {
+            path: path.into(),
+            str,
+            table,
+        });
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub(crate) struct ConfigStamp(Vec<(Box<Path>, Option<SystemTime>)>);
+impl ConfigStamp {
+    fn new(paths: impl IntoIterator<Item = Box<Path>>) -> Self {
+        let mut stamp = Vec::<(Box<Path>, _)>::new();
+        for path in paths {
+            if !stamp.iter().any(|(seen, _)| *seen == path) {
+                let modified = modified(&path);
+                stamp.push((path, modified));
+            }
+        }
+        Self(stamp)
+    }
+    pub(crate) fn is_stale(&self) -> bool {
+        self.0.iter().any(|(path, time)| modified(path) != *time)
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+#[derive(Debug)]
+pub(crate) struct EnvOverride {
+    pub(crate) var: Box<str>,
+    pub(crate) key: Box<str>,
+    pub(crate) value: Value,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+#[serde(default)]
+pub(crate) struct RedirectConfig {
+    pub(crate) config_version: u32,
+    pub(crate) log_path: Option<Box<Path>>,
+    pub(crate) log_level: ConfigLogLevel,
+    pub(crate) only_log_stdout: bool,
+    pub(crate) partial_recovery: bool,
+    pub(crate) hot_reload: bool,
+    pub(crate) filter: EnumeratorFilter,
+    pub(crate) playback: ClientConfig,
+    pub(crate) capture: ClientConfig,
+    pub(crate) device: BTreeMap<Box<str>, ConfigOverlay>,
+    pub(crate) rules: Vec<ClientRule>,
+    #[serde(skip)]
+    pub(crate) raw: Table,
+    #[serde(skip)]
+    pub(crate) source: ConfigSource,
+    #[serde(skip)]
+    pub(crate) layers: Vec<Box<Path>>,
+    #[serde(skip)]
+    pub(crate) origins: BTreeMap<Box<str>, Box<str>>,
+    #[serde(skip)]
+    pub(crate) searched: Vec<Box<Path>>,
+    #[serde(skip)]
+    pub(crate) stamp: ConfigStamp,
+    #[serde(skip)]
+    pub(crate) warnings: Vec<ConfigWarning>,
+    #[serde(skip)]
+    pub(crate) overrides: Vec<EnvOverride>,
+    #[serde(skip)]
+    pub(crate) process: Option<Box<str>>,
+    #[serde(skip)]
+    pub(crate) profile: Option<Box<str>>,
+}
+impl RedirectConfig {
+    pub(crate) fn load(module_dir: Option<&Path>) -> Self {
+        let mut searched = Vec::new();
+        let local = candidates(module_dir).into_iter().find_map(|path| {
+            let str = std::fs::read_to_string(&path);
+            searched.push(path.clone());
+            str.ok().map(|str| (path, str))
+        });
+        Self::load_with(local, searched, process_name())
+    }
+    pub(crate) fn load_with(
+        local: Option<(Box<Path>, String)>,
+        mut searched: Vec<Box<Path>>,
+        process: Option<Box<str>>,
+    ) -> Self {
+        let env = std::env::vars_os()
+            .filter_map(|(var, value)| Some((var.into_string().ok()?, value.into_string().ok()?)))
+            .collect::<Vec<_>>();
+        let global =
+            user_config().filter(|path| local.as_ref().is_none_or(|(local, _)| local != path));
+        let global = global.and_then(|path| {
+            let str = std::fs::read_to_string(&path);
+            searched.push(path.clone());
+            str.ok().map(|str| (path, str))
+        });
+        let (mut files, mut seen, mut warnings) = (Vec::new(), Vec::new(), Vec::new());
+        let mut source = ConfigSource::NoFile;
+        let read = global.into_iter().chain(local).try_for_each(|(path, str)| {
+            ConfigFile::read(&path, str, &mut files, &mut seen, &mut warnings)?;
+            source = ConfigSource::Success(path);
+            Ok(())
+        });
+        let mut config = match read.and_then(|_| Self::parse(&files, process.as_deref(), &env)) {
+            Ok(config) => Self { source, ..config },
+            Err(e) => Self::new_with_source(ConfigSource::NoParse(e)),
+        };
+        warnings.append(&mut config.warnings);
+        config.warnings = warnings;
+        config.process = process;
+        config.stamp = ConfigStamp::new(searched.iter().cloned().chain(seen));
+        config.searched = searched;
+        config
+    }
+    pub(crate) fn parse(
+        files: &[ConfigFile],
+        process: Option<&str>,
+        env: &[(String, String)],
+    ) -> Result<Self, ConfigError> {
+        let mut table = Table::new();
+        let mut origins = BTreeMap::new();
+        for file in files {
+            record_origins(
+                &file.table,
+                "",
+                &file.path.display().to_string(),
+                &mut origins,
+            );
+            merge_table(&mut table, file.table.clone());
+        }
+        let profile = take_profile(&mut table, process).map(|(name, profile)| {
+            record_origins(&profile, "", &format!("profile {name}"), &mut origins);
+            merge_table(&mut table, profile);
+            name
+        });
+        let overrides = Self::default()
+            .known_keys()
+            .map(|schema| apply_env(&mut table, &schema, env))
+            .unwrap_or_default();
+        for EnvOverride { var, key, .. } in &overrides {
+            origins.insert(key.clone(), format!("env {var}").into());
+        }
+        let mut warnings = Vec::new();
+        if table
+            .get("partial_recovery")
+            .and_then(Value::as_bool)
+            .unwrap_or_default()
+        {
+            table.retain(|key, value| {
+                let mut section = Table::new();
+                section.insert(key.to_owned(), value.clone());
+                from_table::<Self>(&section)
+                    .map_err(|e| warnings.push(ConfigWarning::DroppedSection(key.into(), e.into())))
+                    .is_ok()
+            });
+        }
+        let mut config = from_table::<Self>(&table).map_err(|e| {
+            files
+                .iter()
+                .find_map(|file| {
+                    toml::from_str::<Self>(&file.str)
+                        .err()
+                        .map(|e| ConfigError::new(e, &file.str).in_file(&file.path))
+                })
+                .unwrap_or_else(|| e.into())
+        })?;
+        if let Ok(known) = config.known_keys() {
+            unknown_keys(&table, &known, "", &mut warnings);
+        }
+        for flow in ["playback", "capture"] {
+            if let Some(Value::Table(section)) = table.get(flow) {
+                client_keys(section, flow, &mut warnings);
+            }
+        }
+        if let Some(Value::Table(devices)) = table.get("device") {
+            for (pattern, overlay) in devices {
+                if let Value::Table(overlay) = overlay {
+                    overlay_keys(overlay, &key_path("device", pattern), &mut warnings);
+                }
+            }
+        }
+        if let Some(Value::Array(rules)) = table.get("rules") {
+            for (index, rule) in rules.iter().enumerate() {
+                if let Value::Table(rule) = rule {
+                    rule_keys(rule, &format!("rules[{index}]"), &mut warnings);
+                }
+            }
+        }
+        config.profile = profile;
+        config.warnings = warnings;
+        config.overrides = overrides;
+        config.layers = files.iter().map(|file| file.path.clone()).collect();
+        config.origins = origins;
+        config.raw = table;
+        Ok(config)
+    }
+    pub(crate) fn effective_values(&self) -> Vec<(String, Value, &str)> {
+        let mut values = Vec::new();
+        if let Ok(known) = self.known_keys() {
+            collect_values(known, "", &mut values);
+        }
+        values
+            .into_iter()
+            .map(|(key, value)| {
+                let origin = self.origins.get(key.as_str()).map_or(DEFAULT_ORIGIN, |o| o);
+                (key, value, origin)
+            })
+            .collect()
+    }
+    fn known_keys(&self) -> Result<Table, toml::de::Error> {
+        toml::from_str(&toml::to_string(self).unwrap_or_default())
+    }
+    fn new_with_source(source: ConfigSource) -> Self {
+        Self {
+            source,
+            ..Self::default()
+        }
+    }
+    #[inline]
+    pub(crate) fn get(&self, dataflow: DeviceDataFlow) -> &ClientConfig {
+        match dataflow {
+            DeviceDataFlow::Capture => &self.capture,
+            DeviceDataFlow::Playback => &self.playback,
+        }
+    }
+    pub(crate) fn rule(&self, stream: &StreamInfo) -> Option<(Box<str>, &ClientRule)> {
+        self.rules
+            .iter()
+            .enumerate()
+            .find(|(_, rule)| rule.when.matches(stream))
+            .map(|(index, rule)| {
+                let name = rule
+                    .name
+                    .clone()
+                    .unwrap_or_else(|| format!("rules[{index}]").into());
+                (name, rule)
+            })
+    }
+    pub(crate) fn device_overlay<S: AsRef<str>>(
+        &self,
+        names: &[S],
+    ) -> Option<(&str, &ConfigOverlay)> {
+        self.device
+            .iter()
+            .filter_map(|(pattern, overlay)| {
+                let index = names
+                    .iter()
+                    .position(|name| wildcard_match(pattern, name.as_ref()))?;
+                let stars = pattern.matches('*').count();
+                let exact = stars == 0 && !pattern.contains('?');
+                Some(((!exact, stars, index), pattern.as_ref(), overlay))
+            })
+            .min_by_key(|(rank, ..)| *rank)
+            .map(|(_, pattern, overlay)| (pattern, overlay))
+    }
+    pub(crate) fn client_overlay(
+        &self,
+        dataflow: DeviceDataFlow,
+        id: u16,
+    ) -> Option<&ConfigOverlay> {
+        self.get(dataflow).client.get(&id)
+    }
+    pub(crate) fn client_config(
+        &self,
+        dataflow: DeviceDataFlow,
+        overlays: &[&ConfigOverlay],
+    ) -> ClientConfig {
+        if overlays.is_empty() {
+            return self.get(dataflow).clone();
+        }
+        let mut table = self
+            .raw
+            .get(&dataflow.to_string())
+            .and_then(Value::as_table)
+            .cloned()
+            .unwrap_or_default();
+        for overlay in overlays {
+            merge_table(&mut table, overlay.0.clone());
+        }
+        from_table(&table).unwrap_or_else(|e| {
+            warn!("Unable to apply config overrides, error: {e}");
+            self.get(dataflow).clone()
+        })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct EnumeratorFilter {
+    pub(crate) mode: FilterMode,
+    pub(crate) threads: Vec<Box<str>>,
+    pub(crate) modules: Vec<Box<str>>,
+}
+impl Default for EnumeratorFilter {
+    fn default() -> Self {
+        Self {
+            mode: FilterMode::Deny,
+            threads: vec!["[GAME]".into(), "[SK]".into()],
+            modules: Vec::new(),
+        }
+    }
+}
+impl EnumeratorFilter {
+    pub(crate) fn should_wrap(&self, thread: Option<&str>, module: Option<&str>) -> (bool, String) {
+        let matched = thread
+            .and_then(|thread| {
+                self.threads
+                    .iter()
+                    .find(|keyword| thread.contains(keyword.as_ref()))
+                    .map(|keyword| format!("thread {thread} matches `{keyword}`"))
+            })
+            .or_else(|| {
+                module.and_then(|module| {
+                    self.modules
+                        .iter()
+                        .find(|pattern| wildcard_match(pattern, module))
+                        .map(|pattern| format!("module {module} matches `{pattern}`"))
+                })
+            });
+        match (self.mode, matched) {
+            (FilterMode::Deny, Some(reason)) => (false, format!("{reason} in deny list")),
+            (FilterMode::Allow, Some(reason)) => (true, format!("{reason} in allow list")),
+            (mode, None) => (
+                mode == FilterMode::Deny,
+                format!(
+                    "thread {} and module {} match no {} list entry",
+                    thread.unwrap_or("<unnamed>"),
+                    module.unwrap_or("<unknown>"),
+                    mode
+                ),
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy)]
+pub(crate) enum FilterMode {
+    Deny,
+    Allow,
+}
+impl std::fmt::Display for FilterMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Self::Deny => "deny",
+                Self::Allow => "allow",
+            }
+        )
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize)]
+#[serde(transparent)]
+pub(crate) struct ConfigOverlay(Table);
+impl<'de> Deserialize<'de> for ConfigOverlay {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let table = Table::deserialize(deserializer)?;
+        from_table::<ClientConfig>(&table).map_err(|e| de::Error::custom(e.message()))?;
+        Ok(Self(table))
+    }
+}
+
+pub(crate) fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.to_lowercase().chars().collect();
+    let text: Vec<char> = text.to_lowercase().chars().collect();
+    let (mut p, mut t, mut star, mut mark) = (0, 0, None, 0);
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some(p);
+            mark = t;
+            p += 1;
+        } else if let Some(star) = star {
+            p = star + 1;
+            mark += 1;
+            t = mark;
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+fn candidates(module_dir: Option<&Path>) -> Vec<Box<Path>> {
+    let mut candidates: Vec<Box<Path>> = Vec::with_capacity(3);
+    if let Some(path) = std::env::var_os(CONFIG_ENV).map(PathBuf::from) {
+        candidates.push(if path.is_dir() {
+            path.join(CONFIG_NAME).into()
+        } else {
+            path.into()
+        });
+    }
+    for dir in [
+        module_dir.map(Path::to_path_buf),
+        std::env::current_dir().ok(),
+    ]
+    .into_iter()
+    .flatten()
+    {
+        let path = dir.join(CONFIG_NAME).into_boxed_path();
+        if !candidates.contains(&path) {
+            candidates.push(path);
+        }
+    }
+    candidates
+}
+
+pub(crate) struct ProbedEndpoint {
+    pub(crate) flow: DeviceDataFlow,
+    pub(crate) name: String,
+    pub(crate) samplerate: u32,
+    pub(crate) fundamental: u32,
+    pub(crate) period: (u32, u32),
+}
+
+pub(crate) fn generate_config(probed: &[ProbedEndpoint]) -> String {
+    use std::fmt::Write;
+    let mut config = format!(
+        r#"# Generated by wasapi_relink, see the readme for the full key reference.
+
+# Config layout version, older configs without it are migrated with warnings.
+config_version = {CONFIG_VERSION}
+# Path for the log file. "" (empty string) defaults to the working directory.
+log_path = ""
+# Log level: Trace, Debug, Info, Warn, Error, Never
+log_level = "Info"
+# Log only to stdout (true) or to both stdout and file (false).
+only_log_stdout = false
+# Only drop the sections that fail to parse instead of the whole config (bool).
+partial_recovery = false
+# Apply config changes to newly created clients without restarting the game (bool).
+hot_reload = false
+
+[filter]
+# Deny: skip matching enumerator creations, Allow: only wrap matching ones.
+mode = "Deny"
+# Matches if the calling thread's name contains any entry.
+threads = ["[GAME]", "[SK]"]
+# Matches the calling module's file name, * and ? wildcards are supported.
+modules = []
+"#
+    );
+    for flow in [DeviceDataFlow::Playback, DeviceDataFlow::Capture] {
+        _ = write!(
+            config,
+            r#"
+[{flow}]
+# Tool mode, available mode: Normal, Compat, Ringbuf, Bypass
+mode = "Normal"
+# (General) Target device period in 0.1ms units (u32), or a duration string like "3ms", "500us" or "144f".
+# The tool will calculate the closest period *not exceeding* this duration while clamped within driver range.
+# e.g., 20 = 2.0ms, 0 uses the driver's minimum.
+target_period_hus = 0
+# (General) Enable raw process for this stream (bool).
+raw = false
+# (General) Report spoofed period before client initialize.
+force_period = false
+# (General) What to do when the samplerate is missing below: None, Nearest
+samplerate_fallback = "None"
+# (Ringbuf mode exclusive) What to do when the ring buffer is full: DropNewest, DropOldest, Partial
+ring_overflow = "DropNewest"
+# (Playback Ringbuf mode exclusive) What to feed the engine when the ring buffer runs dry: None, Silence, FadeOut, Repeat
+ring_underrun = "None"
+# (Playback Ringbuf mode exclusive) Periods without ReleaseBuffer before the app counts as stalled, 0 disables it.
+ring_stall_periods = 0
+# (Playback Ringbuf mode exclusive) Ring fill to wait for after Start and Reset before playing, e.g. "10ms". 0 disables it.
+ring_start_threshold = 0
+# (Playback Ringbuf mode exclusive) How far the engine buffer is topped up: Fill, Minimal, Hysteresis
+ring_engine_fill = "Fill"
+# (Playback Ringbuf mode exclusive) Periods kept queued by Minimal, low-water mark of Hysteresis.
+ring_engine_periods = 2
+# (Compat and Ringbuf mode, playback) Fade length around Start, Stop, Reset and underruns, e.g. "5ms". 0 disables it.
+fade_len = 0
+"#
+        );
+        let mut endpoints = probed.iter().filter(|endpoint| endpoint.flow == flow);
+        let Some(first) = endpoints.next() else {
+            _ = write!(
+                config,
+                r#"
+# No endpoint was probed, per-samplerate entries look like:
+# target_buffer_len.48000 = 256
+# ring_buffer_len.48000 = 480
+# ring_target_len.48000 = 144
+# compat_buffer_dur_hns.48000 = 0
+"#
+            );
+            continue;
+        };
+        config.push('\n');
+        let mut rates: Vec<(u32, u32, u32)> = Vec::new();
+        for endpoint in std::iter::once(first).chain(endpoints) {
+            let (min, max) = endpoint.period;
+            _ = writeln!(
+                config,
+                "# Probed {}: {}Hz, fundamental {} frames, period {min}..{max} frames",
+                endpoint.name, endpoint.samplerate, endpoint.fundamental
+            );
+            if !rates.iter().any(|(rate, ..)| *rate == endpoint.samplerate) {
+                rates.push((endpoint.samplerate, min, max));
+            }
+        }
+        config.push_str(
+            "# (General) Target buffer length (in audio frames) reported to the corresponding samplerate.\n\
+             # The number will be automatically rounded UP to align with the driver's fundamental period for optimal performance while clamped within range.\n\
+             # Defaults to the full buffer, uncomment to report a shorter one.\n",
+        );
+        for (rate, _, max) in &rates {
+            _ = writeln!(config, "# target_buffer_len.{rate} = {max}");
+        }
+        config.push_str(
+            "# (Ringbuf mode exclusive, Optional) Assign a ring buffer length (in audio frames) to the corresponding samplerate.\n\
+             # The number will be automatically rounded UP to align with the driver's fundamental period for optimal performance.\n",
+        );
+        for (rate, min, _) in &rates {
+            _ = writeln!(config, "ring_buffer_len.{rate} = {}", min * 10);
+        }
+        config.push_str(
+            "# (Playback Ringbuf mode exclusive, Optional) Fill level (in audio frames) the ring buffer slowly converges on.\n\
+             # Frames are dropped or repeated through short crossfades while the highest fill stays away from it, leave it out to disable.\n",
+        );
+        for (rate, min, _) in &rates {
+            _ = writeln!(config, "# ring_target_len.{rate} = {}", min * 3);
+        }
+        config.push_str(
+            "# (Compat mode exclusive, Optional) Assign a shared stream buffer duration (in 100-nanosecond units) to the corresponding samplerate.\n\
+             # The number will be directly used as the inner shared buffer, and will be clamped by Windows if set too low.\n",
+        );
+        for (rate, ..) in &rates {
+            _ = writeln!(config, "compat_buffer_dur_hns.{rate} = 0");
+        }
+    }
+    config
+}
+
+fn canonical(path: &Path) -> Box<Path> {
+    std::fs::canonicalize(path).map_or_else(|_| path.into(), Into::into)
+}
+
+fn user_config() -> Option<Box<Path>> {
+    let dir = PathBuf::from(std::env::var_os("APPDATA")?);
+    Some(dir.join(USER_CONFIG_DIR).join(CONFIG_NAME).into())
+}
+
+fn process_name() -> Option<Box<str>> {
+    std::env::current_exe()
+        .ok()?
+        .file_name()?
+        .to_str()
+        .map(Into::into)
+}
+
+fn take_profile(table: &mut Table, process: Option<&str>) -> Option<(Box<str>, Table)> {
+    let Some(Value::Table(profiles)) = table.remove("profile") else {
+        return None;
+    };
+    let process = process?;
+    profiles
+        .into_iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(process))
+        .and_then(|(name, profile)| match profile {
+            Value::Table(profile) => Some((name.into(), profile)),
+            _ => None,
+        })
+}
+
+fn apply_env(table: &mut Table, schema: &Table, env: &[(String, String)]) -> Vec<EnvOverride> {
+    env.iter()
+        .filter(|(var, _)| var != CONFIG_ENV && var != GENERATE_ENV)
+        .filter_map(|(var, value)| {
+            let path = env_key_path(&var.strip_prefix(ENV_PREFIX)?.to_ascii_lowercase(), schema);
+            let value = toml::from_str::<Table>(&format!("value = {value}"))
+                .ok()
+                .and_then(|mut table| table.remove("value"))
+                .unwrap_or_else(|| Value::String(value.clone()));
+            let (key, parents) = path.split_last()?;
+            let mut target = &mut *table;
+            for parent in parents {
+                let entry = target
+                    .entry(parent.as_str())
+                    .or_insert_with(|| Value::Table(Table::new()));
+                if !entry.is_table() {
+                    *entry = Value::Table(Table::new());
+                }
+                target = entry.as_table_mut()?;
+            }
+            target.insert(key.clone(), value.clone());
+            Some(EnvOverride {
+                var: var.as_str().into(),
+                key: path.join(".").into(),
+                value,
+            })
+        })
+        .collect()
+}
+
+fn env_key_path(name: &str, schema: &Table) -> Vec<String> {
+    let mut keys = schema.iter().collect::<Vec<_>>();
+    keys.sort_by_key(|(key, _)| std::cmp::Reverse(key.len()));
+    for (key, value) in keys {
+        if name == key {
+            return vec![key.clone()];
+        }
+        if let (Some(rest), Value::Table(schema)) = (
+            name.strip_prefix(key.as_str())
+                .and_then(|rest| rest.strip_prefix('_')),
+            value,
+        ) {
+            let mut path = vec![key.clone()];
+            path.extend(env_key_path(rest, schema));
+            return path;
+        }
+    }
+    vec![name.to_owned()]
+}
+
+fn from_table<T: de::DeserializeOwned>(table: &Table) -> Result<T, toml::de::Error> {
+    toml::from_str(&table.to_string())
+}
+
+fn unknown_keys(table: &Table, known: &Table, prefix: &str, warnings: &mut Vec<ConfigWarning>) {
+    for (key, value) in table {
+        let path = key_path(prefix, key);
+        match (known.get(key), value) {
+            (None, _) => warnings.push(ConfigWarning::UnknownKey(path.into())),
+            (Some(Value::Table(known)), Value::Table(table)) => {
+                unknown_keys(table, known, &path, warnings)
+            }
+            _ => {}
+        }
+    }
+}
+
+fn overlay_keys(overlay: &Table, prefix: &str, warnings: &mut Vec<ConfigWarning>) {
+    unknown_fields::<ClientConfig>(overlay, prefix, warnings);
+    client_keys(overlay, prefix, warnings);
+}
+
+fn rule_keys(rule: &Table, prefix: &str, warnings: &mut Vec<ConfigWarning>) {
+    unknown_fields::<ClientRule>(rule, prefix, warnings);
+    if let Some(Value::Table(when)) = rule.get("when") {
+        unknown_fields::<RuleMatch>(when, &key_path(prefix, "when"), warnings);
+    }
+    if let Some(Value::Table(set)) = rule.get("set") {
+        overlay_keys(set, &key_path(prefix, "set"), warnings);
+    }
+}
+
+fn client_keys(section: &Table, prefix: &str, warnings: &mut Vec<ConfigWarning>) {
+    let Some(Value::Table(clients)) = section.get("client") else {
+        return;
+    };
+    for (index, overlay) in clients {
+        if let Value::Table(overlay) = overlay {
+            overlay_keys(overlay, &format!("{prefix}.client.{index}"), warnings);
+        }
+    }
+}
+
+fn unknown_fields<'de, T: Deserialize<'de>>(
+    table: &Table,
+    prefix: &str,
+    warnings: &mut Vec<ConfigWarning>,
+) {
+    let fields = struct_fields::<T>();
+    for key in table.keys() {
+        if !fields.contains(&key.as_str()) {
+            warnings.push(ConfigWarning::UnknownKey(key_path(prefix, key).into()));
+        }
+    }
+}
+
+fn struct_fields<'de, T: Deserialize<'de>>() -> &'static [&'static str] {
+    struct Fields<'a>(&'a mut &'static [&'static str]);
+    impl<'de> Deserializer<'de> for Fields<'_> {
+        type Error = de::value::Error;
+        fn deserialize_any<V: de::Visitor<'de>>(self, _: V) -> Result<V::Value, Self::Error> {
+            Err(de::Error::custom("not a struct"))
+        }
+        fn deserialize_struct<V: de::Visitor<'de>>(
+            self,
+            _: &'static str,
+            fields: &'static [&'static str],
+            _: V,
+        ) -> Result<V::Value, Self::Error> {
+            *self.0 = fields;
+            Err(de::Error::custom("fields collected"))
+        }
+        forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf
+            option unit unit_struct newtype_struct seq tuple tuple_struct map enum identifier
+            ignored_any
+        }
+    }
+    let mut fields: &'static [&'static str] = &[];
+    _ = T::deserialize(Fields(&mut fields));
+    fields
+}
+
+fn migrate(table: &mut Table, path: &Path, warnings: &mut Vec<ConfigWarning>) {
+    let version = table.get("config_version").and_then(Value::as_integer);
+    match version {
+        Some(CONFIG_VERSION) => return,
+        Some(version) if version > CONFIG_VERSION => {
+            warnings.push(ConfigWarning::NewerVersion(path.into(), version));
+            return;
+        }
+        _ => {}
+    }
+    let mut migrated = false;
+    for section in ["playback", "capture"] {
+        let Some(Value::Table(client)) = table.get_mut(section) else {
+            continue;
+        };
+        for (old, new) in migrate_client(client) {
+            migrated = true;
+            warnings.push(ConfigWarning::LegacyKey {
+                file: path.into(),
+                section: section.into(),
+                old,
+                new,
+            });
+        }
+    }
+    if migrated && version.is_none() {
+        warnings.push(ConfigWarning::Unversioned(path.into()));
+    }
+}
+
+fn migrate_client(client: &mut Table) -> Vec<(Box<str>, Option<Box<str>>)> {
+    let mut migrated = Vec::new();
+    for key in [
+        "target_buffer_dur_ms",
+        "compat",
+        "aux_buf_len",
+        "dur_modifier",
+        "inverse",
+    ] {
+        let Some(value) = client.remove(key) else {
+            continue;
+        };
+        let old = format!("{key} = {value}").into();
+        let new = match (key, &value) {
+            ("target_buffer_dur_ms", _) => Some(("target_period_hus", value.clone())),
+            ("compat", Value::Boolean(true)) => Some(("mode", Value::from("Compat"))),
+            ("aux_buf_len", Value::Integer(len @ 1..)) => {
+                Some(("compat_buffer_dur_hns", Value::from(format!("{len}f"))))
+            }
+            _ => None,
+        };
+        let new = new.map(|(key, value)| {
+            let new = format!("{key} = {value}").into();
+            client.entry(key).or_insert(value);
+            new
+        });
+        migrated.push((old, new));
+    }
+    migrated
+}
+
+fn key_path(prefix: &str, key: &str) -> String {
+    if prefix.is_empty() {
+        key.to_owned()
+    } else {
+        format!("{prefix}.{key}")
+    }
+}
+
+fn record_origins(
+    table: &Table,
+    prefix: &str,
+    origin: &str,
+    origins: &mut BTreeMap<Box<str>, Box<str>>,
+) {
+    for (key, value) in table {
+        let path = key_path(prefix, key);
+        match value {
+            Value::Table(table) => record_origins(table, &path, origin, origins),
+            _ => {
+                origins.insert(path.into(), origin.into());
+            }
+        }
+    }
+}
+
+fn collect_values(table: Table, prefix: &str, values: &mut Vec<(String, Value)>) {
+    for (key, value) in table {
+        let path = key_path(prefix, &key);
+        match value {
+            Value::Table(table) if !table.is_empty() => collect_values(table, &path, values),
+            value => values.push((path, value)),
+        }
+    }
+}
+
+pub(crate) fn merge_table(base: &mut Table, overlay: Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(Value::Table(base)), Value::Table(overlay)) => merge_table(base, overlay),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum DeviceDataFlow {
+    Capture,
+    Playback,
+}
+impl std::fmt::Display for DeviceDataFlow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Self::Capture => "capture",
+                Self::Playback => "playback",
+            }
+        )
+    }
+}
+
+pub(crate) const fn calculate_buffer(sample_rate: u32, fundamental: u32, target: u32) -> u32 {
+    sample_rate * target / 10000 / fundamental * fundamental
+}
+
+pub(crate) const fn calculate_period(sample_rate: u32, buffer_len: u32) -> i64 {
+    (buffer_len * 100000 / (sample_rate / 100)) as i64
+}
+
+pub(crate) struct Shared3Info {
+    pub(crate) current_period: u32,
+    pub(crate) samplerate: u32,
+    pub(crate) fundamental: u32,
+    pub(crate) fallbacks: Vec<(&'static str, u32)>,
+    ring_buffer_len: Option<ConfigDuration>,
+    ring_target_len: Option<ConfigDuration>,
+    target_buffer_len: Option<ConfigDuration>,
+    compat_buffer_dur: Option<ConfigDuration>,
+}
+impl Shared3Info {
+    pub(crate) fn new(
+        config: &ClientConfig,
+        samplerate: u32,
+        fundamental: u32,
+        (min, max): (u32, u32),
+    ) -> Self {
+        let mut fallbacks = Vec::new();
+        let mut resolve = |key, map: &RateMap, raw_frames| {
+            let (len, rate) = map.resolve(samplerate, config.samplerate_fallback, raw_frames)?;
+            if let Some(rate) = rate {
+                fallbacks.push((key, rate));
+            }
+            Some(len)
+        };
+        let ring_buffer_len = resolve("ring_buffer_len", &config.ring_buffer_len, true);
+        let ring_target_len = resolve("ring_target_len", &config.ring_target_len, true);
+        let target_buffer_len = resolve("target_buffer_len", &config.target_buffer_len, true);
+        let compat_buffer_dur = resolve(
+            "compat_buffer_dur_hns",
+            &config.compat_buffer_dur_hns,
+            false,
+        );
+        Self {
+            current_period: config
+                .period_len(samplerate, fundamental)
+                .map_or(min, |len| len.clamp(min, max)),
+            samplerate,
+            fundamental,
+            fallbacks,
+            ring_buffer_len,
+            ring_target_len,
+            target_buffer_len,
+            compat_buffer_dur,
+        }
+    }
+    pub(crate) fn target_buf_len(&self) -> Option<u32> {
+        self.target_buffer_len.map(|l| {
+            l.ceil_frames(self.samplerate)
+                .next_multiple_of(self.fundamental)
+        })
+    }
+    pub(crate) fn ring_buf_len(&self) -> Option<u32> {
+        self.ring_buffer_len.map(|l| {
+            self.current_period.max(
+                l.ceil_frames(self.samplerate)
+                    .next_multiple_of(self.fundamental),
+            )
+        })
+    }
+    pub(crate) fn ring_target(&self) -> Option<u32> {
+        self.ring_target_len.map(|l| l.ceil_frames(self.samplerate))
+    }
+    pub(crate) fn compat_buf_len(&self) -> Option<i64> {
+        self.compat_buffer_dur.map(|l| l.hns(self.samplerate))
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
+#[serde(default)]
+pub(crate) struct ClientConfig {
+    #[serde(deserialize_with = "ConfigDuration::deserialize_hus")]
+    pub(crate) target_period_hus: ConfigDuration,
+    #[serde(deserialize_with = "RateMap::deserialize_len")]
+    pub(crate) ring_buffer_len: RateMap,
+    #[serde(deserialize_with = "RateMap::deserialize_len")]
+    pub(crate) ring_target_len: RateMap,
+    #[serde(deserialize_with = "RateMap::deserialize_len")]
+    pub(crate) target_buffer_len: RateMap,
+    pub(crate) compat_buffer_dur_hns: RateMap,
+    pub(crate) samplerate_fallback: RateFallback,
+    pub(crate) ring_overflow: RingOverflow,
+    pub(crate) ring_underrun: RingUnderrun,
+    pub(crate) ring_stall_periods: u32,
+    pub(crate) ring_start_threshold: ConfigDuration,
+    pub(crate) ring_engine_fill: EngineFill,
+    pub(crate) ring_engine_periods: Option<u32>,
+    pub(crate) fade_len: ConfigDuration,
+    pub(crate) force_period: bool,
+    pub(crate) mode: ClientMode,
+    pub(crate) raw: bool,
+    pub(crate) client: BTreeMap<u16, ConfigOverlay>,
+}
+impl ClientConfig {
+    pub(crate) fn period_len(&self, samplerate: u32, fundamental: u32) -> Option<u32> {
+        let len = match self.target_period_hus {
+            ConfigDuration::Raw(0) => return None,
+            ConfigDuration::Raw(hus) => calculate_buffer(
+                samplerate,
+                fundamental,
+                u32::try_from(hus).unwrap_or(u32::MAX),
+            ),
+            duration => duration.frames(samplerate) / fundamental * fundamental,
+        };
+        Some(len)
+    }
+    pub(crate) fn start_threshold(&self, samplerate: u32) -> u32 {
+        self.ring_start_threshold.ceil_frames(samplerate)
+    }
+    pub(crate) fn engine_mark(&self, info: &Shared3Info) -> u32 {
+        info.current_period * self.ring_engine_periods.unwrap_or(2).max(1)
+    }
+    pub(crate) fn fade_frames(&self, samplerate: u32) -> u32 {
+        self.fade_len.ceil_frames(samplerate)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum ConfigDuration {
+    Raw(i64),
+    Millis(f64),
+    Frames(u32),
+}
+impl Default for ConfigDuration {
+    fn default() -> Self {
+        Self::Raw(0)
+    }
+}
+impl ConfigDuration {
+    fn frames(self, samplerate: u32) -> u32 {
+        match self {
+            Self::Raw(len) => u32::try_from(len).unwrap_or(u32::MAX),
+            Self::Millis(ms) => (samplerate as f64 * ms / 1000.0) as u32,
+            Self::Frames(len) => len,
+        }
+    }
+    fn ceil_frames(self, samplerate: u32) -> u32 {
+        match self {
+            Self::Millis(ms) => (samplerate as f64 * ms / 1000.0).ceil() as u32,
+            _ => self.frames(samplerate),
+        }
+    }
+    fn hns(self, samplerate: u32) -> i64 {
+        match self {
+            Self::Raw(hns) => hns,
+            Self::Millis(ms) => (ms * 10000.0) as i64,
+            Self::Frames(len) => len as i64 * 10000000 / samplerate as i64,
+        }
+    }
+    fn deserialize_hus<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        match Self::deserialize(deserializer)? {
+            Self::Raw(hus) if u32::try_from(hus).is_err() => Err(de::Error::custom(format!(
+                "invalid duration `{hus}`, out of range"
+            ))),
+            duration => Ok(duration),
+        }
+    }
+    fn check_len(self) -> Result<(), String> {
+        if matches!(self, Self::Raw(0) | Self::Frames(0)) || self == Self::Millis(0.0) {
+            Err(format!(
+                "invalid length `{self}`, expected a non-zero length"
+            ))
+        } else if let Self::Raw(len) = self
+            && u32::try_from(len).is_err()
+        {
+            Err(format!("invalid length `{len}`, out of range"))
+        } else {
+            Ok(())
+        }
+    }
+}
+impl std::str::FromStr for ConfigDuration {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let parse_ms = |num: &str, scale: f64| {
+            num.trim()
+                .parse::<f64>()
+                .ok()
+                .filter(|ms| ms.is_finite() && *ms >= 0.0)
+                .map(|ms| Self::Millis(ms * scale))
+        };
+        if let Some(num) = s.strip_suffix("ms") {
+            parse_ms(num, 1.0)
+        } else if let Some(num) = s.strip_suffix("us") {
+            parse_ms(num, 0.001)
+        } else if let Some(num) = s.strip_suffix('f') {
+            num.trim().parse().ok().map(Self::Frames)
+        } else {
+            None
+        }
+        .ok_or_else(|| {
+            format!("invalid duration `{s}`, expected e.g. \"2ms\", \"500us\" or \"340f\"")
+        })
+    }
+}
+impl std::fmt::Display for ConfigDuration {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Raw(len) => write!(f, "{len}"),
+            Self::Millis(ms) => write!(f, "{ms}ms"),
+            Self::Frames(len) => write!(f, "{len}f"),
+        }
+    }
+}
+impl Serialize for ConfigDuration {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Self::Raw(len) => serializer.serialize_i64(*len),
+            _ => serializer.collect_str(self),
+        }
+    }
+}
+impl<'de> Deserialize<'de> for ConfigDuration {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct DurationVisitor;
+        impl de::Visitor<'_> for DurationVisitor {
+            type Value = ConfigDuration;
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a non-negative integer or a duration string")
+            }
+            fn visit_i64<E: de::Error>(self, v: i64) -> Result<Self::Value, E> {
+                if v < 0 {
+                    Err(E::invalid_value(de::Unexpected::Signed(v), &self))
+                } else {
+                    Ok(ConfigDuration::Raw(v))
+                }
+            }
+            fn visit_u64<E: de::Error>(self, v: u64) -> Result<Self::Value, E> {
+                i64::try_from(v)
+                    .map(ConfigDuration::Raw)
+                    .map_err(|_| E::invalid_value(de::Unexpected::Unsigned(v), &self))
+            }
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+                v.parse().map_err(E::custom)
+            }
+        }
+        deserializer.deserialize_any(DurationVisitor)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum RateMap {
+    All(ConfigDuration),
+    PerRate(HashMap<u32, ConfigDuration>),
+}
+impl Default for RateMap {
+    fn default() -> Self {
+        Self::PerRate(HashMap::new())
+    }
+}
+impl RateMap {
+    pub(crate) fn get(&self, samplerate: u32) -> Option<ConfigDuration> {
+        match self {
+            Self::All(duration) => Some(*duration),
+            Self::PerRate(map) => map.get(&samplerate).copied(),
+        }
+    }
+    pub(crate) fn resolve(
+        &self,
+        samplerate: u32,
+        fallback: RateFallback,
+        raw_frames: bool,
+    ) -> Option<(ConfigDuration, Option<u32>)> {
+        let (Self::PerRate(map), None, RateFallback::Nearest) =
+            (self, self.get(samplerate), fallback)
+        else {
+            return self.get(samplerate).map(|duration| (duration, None));
+        };
+        let (&rate, &duration) = map
+            .iter()
+            .min_by_key(|(rate, _)| (rate.abs_diff(samplerate), std::cmp::Reverse(**rate)))?;
+        let scale = |len: u32| {
+            ConfigDuration::Frames((len as u64 * samplerate as u64).div_ceil(rate as u64) as u32)
+        };
+        let resolved = match duration {
+            ConfigDuration::Frames(len) => scale(len),
+            ConfigDuration::Raw(len) if raw_frames => scale(u32::try_from(len).unwrap_or(u32::MAX)),
+            duration => duration,
+        };
+        Some((resolved, Some(rate)))
+    }
+    fn deserialize_len<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let map = Self::deserialize(deserializer)?;
+        match &map {
+            Self::All(duration) => duration.check_len(),
+            Self::PerRate(rates) => rates.values().try_for_each(|duration| duration.check_len()),
+        }
+        .map_err(de::Error::custom)?;
+        Ok(map)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Default, PartialEq, Eq, Clone, Copy)]
+pub(crate) enum RateFallback {
+    #[default]
+    None,
+    Nearest,
+}
+impl Serialize for RateMap {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Self::All(duration) => duration.serialize(serializer),
+            Self::PerRate(map) => map.serialize(serializer),
+        }
+    }
+}
+impl<'de> Deserialize<'de> for RateMap {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct RateMapVisitor;
+        impl<'de> de::Visitor<'de> for RateMapVisitor {
+            type Value = RateMap;
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a duration or a table of samplerate to duration")
+            }
+            fn visit_i64<E: de::Error>(self, v: i64) -> Result<Self::Value, E> {
+                ConfigDuration::deserialize(de::value::I64Deserializer::new(v)).map(RateMap::All)
+            }
+            fn visit_u64<E: de::Error>(self, v: u64) -> Result<Self::Value, E> {
+                ConfigDuration::deserialize(de::value::U64Deserializer::new(v)).map(RateMap::All)
+            }
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+                v.parse().map(RateMap::All).map_err(E::custom)
+            }
+            fn visit_map<A: de::MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+                let mut rates = HashMap::with_capacity(map.size_hint().unwrap_or_default());
+                while let Some((rate, duration)) = map.next_entry::<u32, ConfigDuration>()? {
+                    rates.insert(rate, duration);
+                }
+                Ok(RateMap::PerRate(rates))
+            }
+        }
+        deserializer.deserialize_any(RateMapVisitor)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Default, PartialEq, Eq, Clone, Copy)]
+pub(crate) enum RingOverflow {
+    #[default]
+    DropNewest,
+    DropOldest,
+    Partial,
+}
+impl RingOverflow {
+    pub(crate) fn fit(self, len: usize, free: usize, slots: usize) -> usize {
+        match self {
+            _ if len <= free => len,
+            Self::DropNewest => 0,
+            Self::DropOldest if len <= slots => len,
+            Self::DropOldest => 0,
+            Self::Partial => free,
+        }
+    }
+}
+impl std::fmt::Display for RingOverflow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Self::DropNewest => "drop newest",
+                Self::DropOldest => "drop oldest",
+                Self::Partial => "partial",
+            }
+        )
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Default, PartialEq, Eq, Clone, Copy)]
+pub(crate) enum RingUnderrun {
+    #[default]
+    None,
+    Silence,
+    FadeOut,
+    Repeat,
+}
+impl std::fmt::Display for RingUnderrun {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Self::None => "none",
+                Self::Silence => "silence",
+                Self::FadeOut => "fade out",
+                Self::Repeat => "repeat",
+            }
+        )
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Default, PartialEq, Eq, Clone, Copy)]
+pub(crate) enum EngineFill {
+    #[default]
+    Fill,
+    Minimal,
+    Hysteresis,
+}
+impl EngineFill {
+    /// Frames to write into an engine buffer of `len` frames holding `pad`.
+    pub(crate) fn room(self, pad: u32, len: u32, mark: u32) -> u32 {
+        match self {
+            Self::Fill => len - pad,
+            Self::Minimal => mark.min(len).saturating_sub(pad),
+            Self::Hysteresis if pad < mark.min(len) => len - pad,
+            Self::Hysteresis => 0,
+        }
+    }
+    pub(crate) fn queued(self, len: u32, mark: u32) -> u32 {
+        match self {
+            Self::Minimal => mark.min(len),
+            _ => len,
+        }
+    }
+}
+impl std::fmt::Display for EngineFill {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Self::Fill => "fill",
+                Self::Minimal => "minimal",
+                Self::Hysteresis => "hysteresis",
+            }
+        )
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Default, PartialEq, Eq, Clone, Copy)]
+pub(crate) enum ClientMode {
+    #[default]
+    Normal,
+    Compat,
+    Ringbuf,
+    Bypass,
+}
+impl std::fmt::Display for ClientMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Self::Normal => "normal",
+                Self::Compat => "compat",
+                Self::Ringbuf => "ringbuf",
+                Self::Bypass => "bypass",
+            }
+        )
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct ClientRule {
+    pub(crate) name: Option<Box<str>>,
+    pub(crate) when: RuleMatch,
+    pub(crate) set: ConfigOverlay,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct RuleMatch {
+    pub(crate) flow: Option<DeviceDataFlow>,
+    pub(crate) role: Option<StreamRole>,
+    pub(crate) category: Option<StreamCategory>,
+    pub(crate) channels: Option<u16>,
+    pub(crate) samplerate: Option<u32>,
+    pub(crate) stream_flags: Vec<StreamFlag>,
+    pub(crate) session: Option<Box<str>>,
+}
+impl RuleMatch {
+    fn matches(&self, stream: &StreamInfo) -> bool {
+        self.flow.is_none_or(|flow| flow == stream.flow)
+            && self.role.is_none_or(|role| Some(role) == stream.role)
+            && self
+                .category
+                .is_none_or(|category| Some(category) == stream.category)
+            && self
+                .channels
+                .is_none_or(|channels| channels == stream.channels)
+            && self
+                .samplerate
+                .is_none_or(|samplerate| samplerate == stream.samplerate)
+            && self
+                .stream_flags
+                .iter()
+                .all(|flag| stream.stream_flags & flag.bits() != 0)
+            && self.session.as_ref().is_none_or(|session| {
+                session
+                    .trim_matches(['{', '}'])
+                    .eq_ignore_ascii_case(stream.session.trim_matches(['{', '}']))
+            })
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct StreamInfo {
+    pub(crate) flow: DeviceDataFlow,
+    pub(crate) role: Option<StreamRole>,
+    pub(crate) category: Option<StreamCategory>,
+    pub(crate) channels: u16,
+    pub(crate) samplerate: u32,
+    pub(crate) stream_flags: u32,
+    pub(crate) session: Box<str>,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy)]
+pub(crate) enum StreamRole {
+    Console,
+    Multimedia,
+    Communications,
+}
+impl StreamRole {
+    pub(crate) fn from_raw(role: i32) -> Option<Self> {
+        match role {
+            0 => Some(Self::Console),
+            1 => Some(Self::Multimedia),
+            2 => Some(Self::Communications),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy)]
+pub(crate) enum StreamCategory {
+    Other,
+    ForegroundOnlyMedia,
+    Communications,
+    Alerts,
+    SoundEffects,
+    GameEffects,
+    GameMedia,
+    GameChat,
+    Speech,
+    Movie,
+    Media,
+    FarFieldSpeech,
+    UniformSpeech,
+    VoiceTyping,
+}
+impl StreamCategory {
+    pub(crate) fn from_raw(category: i32) -> Option<Self> {
+        Some(match category {
+            0 => Self::Other,
+            1 => Self::ForegroundOnlyMedia,
+            3 => Self::Communications,
+            4 => Self::Alerts,
+            5 => Self::SoundEffects,
+            6 => Self::GameEffects,
+            7 => Self::GameMedia,
+            8 => Self::GameChat,
+            9 => Self::Speech,
+            10 => Self::Movie,
+            11 => Self::Media,
+            12 => Self::FarFieldSpeech,
+            13 => Self::UniformSpeech,
+            14 => Self::VoiceTyping,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy)]
+pub(crate) enum StreamFlag {
+    CrossProcess,
+    Loopback,
+    EventCallback,
+    NoPersist,
+    RateAdjust,
+    SrcDefaultQuality,
+    AutoConvertPcm,
+}
+impl StreamFlag {
+    fn bits(self) -> u32 {
+        match self {
+            Self::CrossProcess => 0x10000,
+            Self::Loopback => 0x20000,
+            Self::EventCallback => 0x40000,
+            Self::NoPersist => 0x80000,
+            Self::RateAdjust => 0x100000,
+            Self::SrcDefaultQuality => 0x8000000,
+            Self::AutoConvertPcm => 0x80000000,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(str: &str) -> Table {
+        toml::from_str(str).unwrap()
+    }
+
+    fn file(path: &str, str: &str) -> ConfigFile {
+        ConfigFile {
+            path: Path::new(path).into(),
+            str: str.into(),
+            table: table(str),
+        }
+    }
+
+    fn env(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(var, value)| (var.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn stall(files: &[ConfigFile], process: Option<&str>, env: &[(String, String)]) -> u32 {
+        RedirectConfig::parse(files, process, env)
+            .unwrap()
+            .playback
+            .ring_stall_periods
+    }
+
+    #[test]
+    fn layers_in_order() {
+        let user = file(
+            "user.toml",
+            "[playback]\nring_stall_periods = 1\nring_underrun = \"Silence\"",
+        );
+        let include = file("include.toml", "[playback]\nring_stall_periods = 2");
+        let local = file("local.toml", "[playback]\nring_stall_periods = 3");
+        let mut files = vec![user];
+        assert_eq!(stall(&files, None, &[]), 1);
+        files.push(include);
+        assert_eq!(stall(&files, None, &[]), 2);
+        files.push(local);
+        assert_eq!(stall(&files, None, &[]), 3);
+
+        let config = RedirectConfig::parse(&files, None, &[]).unwrap();
+        assert_eq!(config.playback.ring_underrun, RingUnderrun::Silence);
+        assert_eq!(
+            config.origins["playback.ring_stall_periods"].as_ref(),
+            "local.toml"
+        );
+        assert_eq!(
+            config.origins["playback.ring_underrun"].as_ref(),
+            "user.toml"
+        );
+    }
+
+    #[test]
+    fn includes_come_before_the_including_file() {
+        let dir = std::env::temp_dir().join(format!("relink-include-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("shared.toml"),
+            "[playback]\nring_stall_periods = 2",
+        )
+        .unwrap();
+        let local = dir.join("local.toml");
+        let str =
+            "include = [\"shared.toml\", \"missing.toml\"]\n[playback]\nring_stall_periods = 3";
+        let (mut files, mut seen, mut warnings) = (Vec::new(), Vec::new(), Vec::new());
+        ConfigFile::read(&local, str.into(), &mut files, &mut seen, &mut warnings).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(files.len(), 2);
+        assert!(files[0].path.ends_with("shared.toml"));
+        assert!(matches!(&warnings[..], [ConfigWarning::MissingInclude(..)]));
+        assert_eq!(stall(&files, None, &[]), 3);
+    }
+
+    #[test]
+    fn profile_layers_over_the_global_config() {
+        let files = [file(
+            "local.toml",
+            "[playback]\nring_stall_periods = 3\nring_underrun = \"Silence\"\n[profile.\"Game.exe\".playback]\nring_stall_periods = 4",
+        )];
+        assert_eq!(stall(&files, None, &[]), 3);
+        assert_eq!(stall(&files, Some("other.exe"), &[]), 3);
+
+        let config = RedirectConfig::parse(&files, Some("game.exe"), &[]).unwrap();
+        assert_eq!(config.playback.ring_stall_periods, 4);
+        assert_eq!(config.playback.ring_underrun, RingUnderrun::Silence);
+        assert_eq!(config.profile.as_deref(), Some("Game.exe"));
+        assert_eq!(
+            config.origins["playback.ring_stall_periods"].as_ref(),
+            "profile Game.exe"
+        );
+    }
+
+    #[test]
+    fn takes_profile() {
+        let profiles = "log_level = \"Info\"\n[profile.\"Game.exe\"]\nhot_reload = true";
+        let mut config = table(profiles);
+        let (name, profile) = take_profile(&mut config, Some("GAME.EXE")).unwrap();
+        assert_eq!(name.as_ref(), "Game.exe");
+        assert_eq!(profile, table("hot_reload = true"));
+        assert!(!config.contains_key("profile"));
+
+        let mut config = table(profiles);
+        assert!(take_profile(&mut config, Some("other.exe")).is_none());
+        assert!(!config.contains_key("profile"));
+
+        let mut config = table(profiles);
+        assert!(take_profile(&mut config, None).is_none());
+        assert!(!config.contains_key("profile"));
+    }
+
+    #[test]
+    fn env_overrides_every_layer() {
+        let files = [file(
+            "local.toml",
+            "[playback]\nring_stall_periods = 3\n[profile.\"Game.exe\".playback]\nring_stall_periods = 4",
+        )];
+        let vars = env(&[("WASAPI_RELINK_PLAYBACK_RING_STALL_PERIODS", "5")]);
+        assert_eq!(stall(&files, None, &vars), 5);
+        assert_eq!(stall(&files, Some("game.exe"), &vars), 5);
+
+        let config = RedirectConfig::parse(&files, Some("game.exe"), &vars).unwrap();
+        assert_eq!(
+            config.origins["playback.ring_stall_periods"].as_ref(),
+            "env WASAPI_RELINK_PLAYBACK_RING_STALL_PERIODS"
+        );
+        assert_eq!(config.overrides.len(), 1);
+    }
+
+    #[test]
+    fn partial_recovery_drops_only_the_broken_section() {
+        let str = "partial_recovery = true\nlog_level = \"Loud\"\n[playback]\nring_stall_periods = 3\n[capture]\nmode = \"Bogus\"";
+        let config = RedirectConfig::parse(&[file("local.toml", str)], None, &[]).unwrap();
+        assert_eq!(config.playback.ring_stall_periods, 3);
+        assert_eq!(config.capture.mode, ClientMode::default());
+        let dropped = config
+            .warnings
+            .iter()
+            .filter_map(|warning| match warning {
+                ConfigWarning::DroppedSection(key, _) => Some(key.as_ref()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(dropped, ["capture", "log_level"]);
+
+        let str = "log_level = \"Loud\"\n[playback]\nring_stall_periods = 3";
+        assert!(RedirectConfig::parse(&[file("local.toml", str)], None, &[]).is_err());
+    }
+
+    #[test]
+    fn matches_wildcards() {
+        assert!(wildcard_match("Speakers", "speakers"));
+        assert!(wildcard_match("*", ""));
+        assert!(wildcard_match("*USB*", "Headphones (USB Audio)"));
+        assert!(wildcard_match("{0.0.0.*}.{*}", "{0.0.0.00000000}.{1234}"));
+        assert!(wildcard_match("Game?.exe", "game1.exe"));
+        assert!(wildcard_match("a*b*c", "aXXbYYc"));
+        assert!(!wildcard_match("Game?.exe", "game.exe"));
+        assert!(!wildcard_match("*USB", "USB Audio"));
+        assert!(!wildcard_match("Speakers", "Speakers 2"));
+    }
+
+    #[test]
+    fn picks_the_most_specific_device() {
+        let str = "[device.\"USB*\"]\n[device.\"*USB*\"]\n[device.\"USB Audio\"]\n[device.\"{0.0.0.00000000}.{1234}\"]\n[device.\"?SB Audio\"]";
+        let config = RedirectConfig::parse(&[file("local.toml", str)], None, &[]).unwrap();
+        let pick = |names: &[&str]| config.device_overlay(names).map(|(pattern, _)| pattern);
+        let (id, other) = ("{0.0.0.00000000}.{1234}", "{0.0.0.00000000}.{5678}");
+        assert_eq!(pick(&[id, "USB Audio"]), Some(id));
+        assert_eq!(pick(&[other, "usb audio"]), Some("USB Audio"));
+        assert_eq!(pick(&[other, "XSB Audio"]), Some("?SB Audio"));
+        assert_eq!(pick(&[other, "USB Audio 2"]), Some("USB*"));
+        assert_eq!(pick(&[other, "Speakers (USB)"]), Some("*USB*"));
+        assert_eq!(pick(&[other, "Speakers"]), None);
+    }
+
+    #[test]
+    fn warns_about_unknown_overlay_keys() {
+        let str = "[playback]\nmdoe = \"Ringbuf\"\n[playback.client.1]\nmode = \"Compat\"\nraww = true\n[device.\"USB*\"]\nmdoe = \"Ringbuf\"\n[device.\"USB*\".client.0]\nfade = 1";
+        let config = RedirectConfig::parse(&[file("local.toml", str)], None, &[]).unwrap();
+        let unknown = config
+            .warnings
+            .iter()
+            .filter_map(|warning| match warning {
+                ConfigWarning::UnknownKey(key) => Some(key.as_ref()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            unknown,
+            [
+                "playback.mdoe",
+                "playback.client.1.raww",
+                "device.USB*.mdoe",
+                "device.USB*.client.0.fade"
+            ]
+        );
+    }
+
+    #[test]
+    fn warns_about_unknown_rule_keys() {
+        let str = "[[rules]]\nname = \"voice\"\nwhen.flwo = \"Capture\"\nset.mdoe = \"Ringbuf\"\n[[rules]]\nwhen.role = \"Communications\"\nset.mode = \"Compat\"\nsett.mode = \"Bypass\"";
+        let config = RedirectConfig::parse(&[file("local.toml", str)], None, &[]).unwrap();
+        assert_eq!(config.rules.len(), 2);
+        let unknown = config
+            .warnings
+            .iter()
+            .filter_map(|warning| match warning {
+                ConfigWarning::UnknownKey(key) => Some(key.as_ref()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            unknown,
+            ["rules[0].when.flwo", "rules[0].set.mdoe", "rules[1].sett"]
+        );
+    }
+
+    #[test]
+    fn parses_env_keys() {
+        let schema = RedirectConfig::default().known_keys().unwrap();
+        assert_eq!(
+            env_key_path("playback_ring_stall_periods", &schema),
+            ["playback", "ring_stall_periods"]
+        );
+        assert_eq!(env_key_path("filter_mode", &schema), ["filter", "mode"]);
+        assert_eq!(env_key_path("hot_reload", &schema), ["hot_reload"]);
+
+        let mut config = Table::new();
+        let overrides = apply_env(
+            &mut config,
+            &schema,
+            &env(&[
+                ("WASAPI_RELINK_PLAYBACK_MODE", "Ringbuf"),
+                ("WASAPI_RELINK_CAPTURE_RAW", "true"),
+                ("WASAPI_RELINK_CONFIG", "other.toml"),
+                ("PATH", "C:\\Windows"),
+            ]),
+        );
+        assert_eq!(overrides.len(), 2);
+        assert_eq!(
+            config,
+            table("[playback]\nmode = \"Ringbuf\"\n[capture]\nraw = true")
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_lengths() {
+        let parse = |str: &str| toml::from_str::<ClientConfig>(str);
+        for str in [
+            "ring_buffer_len = 0",
+            "target_buffer_len.48000 = 0",
+            "target_buffer_len.48000 = \"0f\"",
+            "ring_target_len = \"0ms\"",
+            "ring_buffer_len.48000 = 4294967296",
+            "target_period_hus = 4294967296",
+        ] {
+            assert!(parse(str).is_err(), "{str}");
+        }
+        for str in [
+            "target_period_hus = 0",
+            "fade_len = 0",
+            "ring_start_threshold = \"0ms\"",
+            "compat_buffer_dur_hns.48000 = 0",
+            "ring_buffer_len.48000 = 340",
+        ] {
+            assert!(parse(str).is_ok(), "{str}");
+        }
+    }
+
+    #[test]
+    fn resolves_rates() {
+        let map =
+            toml::from_str::<ClientConfig>("ring_buffer_len = { 44100 = 441, 48000 = \"480f\" }")
+                .unwrap()
+                .ring_buffer_len;
+        let resolve =
+            |samplerate, fallback, raw_frames| map.resolve(samplerate, fallback, raw_frames);
+        let nearest = RateFallback::Nearest;
+
+        assert_eq!(
+            resolve(48000, nearest, true),
+            Some((ConfigDuration::Frames(480), None))
+        );
+        assert_eq!(
+            resolve(44100, RateFallback::None, true),
+            Some((ConfigDuration::Raw(441), None))
+        );
+        assert_eq!(resolve(96000, RateFallback::None, true), None);
+        assert_eq!(
+            resolve(96000, nearest, true),
+            Some((ConfigDuration::Frames(960), Some(48000)))
+        );
+        assert_eq!(
+            resolve(22050, nearest, true),
+            Some((ConfigDuration::Frames(221), Some(44100)))
+        );
+        assert_eq!(
+            resolve(22050, nearest, false),
+            Some((ConfigDuration::Raw(441), Some(44100)))
+        );
+        assert_eq!(
+            resolve(46050, nearest, true),
+            Some((ConfigDuration::Frames(461), Some(48000)))
+        );
+
+        let map = RateMap::PerRate(HashMap::from([(48000, ConfigDuration::Millis(10.0))]));
+        assert_eq!(
+            map.resolve(96000, nearest, true),
+            Some((ConfigDuration::Millis(10.0), Some(48000)))
+        );
+        let map = RateMap::All(ConfigDuration::Frames(256));
+        assert_eq!(
+            map.resolve(96000, nearest, true),
+            Some((ConfigDuration::Frames(256), None))
+        );
+    }
+
+    #[test]
+    fn migrates_legacy_keys() {
+        let mut client = table(
+            "target_buffer_dur_ms = 30\ncompat = true\naux_buf_len = 256\ninverse = true\ndur_modifier = 2",
+        );
+        let migrated = migrate_client(&mut client);
+        assert_eq!(migrated.len(), 5);
+        assert_eq!(
+            client,
+            table("target_period_hus = 30\nmode = \"Compat\"\ncompat_buffer_dur_hns = \"256f\"")
+        );
+        let removed = migrated.iter().filter(|(_, new)| new.is_none()).count();
+        assert_eq!(removed, 2);
+
+        let mut client = table("compat = false\nmode = \"Ringbuf\"\naux_buf_len = 0");
+        migrate_client(&mut client);
+        assert_eq!(client, table("mode = \"Ringbuf\""));
+    }
+
+    #[test]
+    fn migrates_by_version() {
+        let path = Path::new("local.toml");
+        let legacy = "[playback]\ncompat = true";
+
+        let mut config = table(legacy);
+        let mut warnings = Vec::new();
+        migrate(&mut config, path, &mut warnings);
+        assert_eq!(config, table("[playback]\nmode = \"Compat\""));
+        assert!(matches!(
+            &warnings[..],
+            [
+                ConfigWarning::LegacyKey { .. },
+                ConfigWarning::Unversioned(_)
+            ]
+        ));
+
+        for (version, newer) in [(CONFIG_VERSION, false), (CONFIG_VERSION + 1, true)] {
+            let mut config = table(&format!("config_version = {version}\n{legacy}"));
+            let before = config.clone();
+            let mut warnings = Vec::new();
+            migrate(&mut config, path, &mut warnings);
+            assert_eq!(config, before);
+            assert_eq!(
+                matches!(&warnings[..], [ConfigWarning::NewerVersion(..)]),
+                newer
+            );
+            assert_eq!(warnings.is_empty(), !newer);
+        }
+    }
+}
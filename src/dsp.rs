@@ -0,0 +1,648 @@
+use rtrb::chunks::ChunkError;
+use rtrb::{Consumer, Producer};
+use std::f64::consts::PI;
+use std::mem::MaybeUninit;
+
+const WINDOW: u32 = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SampleFormat {
+    U8,
+    I16,
+    I24,
+    I32,
+    F32,
+    F64,
+}
+impl SampleFormat {
+    pub(crate) fn new(float: bool, bits: u16) -> Option<Self> {
+        Some(match (float, bits) {
+            (false, 8) => Self::U8,
+            (false, 16) => Self::I16,
+            (false, 24) => Self::I24,
+            (false, 32) => Self::I32,
+            (true, 32) => Self::F32,
+            (true, 64) => Self::F64,
+            _ => return None,
+        })
+    }
+    pub(crate) fn bytes(self) -> usize {
+        match self {
+            Self::U8 => 1,
+            Self::I16 => 2,
+            Self::I24 => 3,
+            Self::I32 | Self::F32 => 4,
+            Self::F64 => 8,
+        }
+    }
+    pub(crate) fn read(self, sample: &[u8]) -> f64 {
+        match self {
+            Self::U8 => (sample[0] as f64 - 128.0) / 128.0,
+            Self::I16 => i16::from_le_bytes([sample[0], sample[1]]) as f64 / 32768.0,
+            Self::I24 => {
+                (i32::from_le_bytes([0, sample[0], sample[1], sample[2]]) >> 8) as f64 / 8388608.0
+            }
+            Self::I32 => i32::from_le_bytes(sample[..4].try_into().unwrap()) as f64 / 2147483648.0,
+            Self::F32 => f32::from_le_bytes(sample[..4].try_into().unwrap()) as f64,
+            Self::F64 => f64::from_le_bytes(sample[..8].try_into().unwrap()),
+        }
+    }
+    pub(crate) fn write(self, sample: &mut [u8], value: f64) {
+        let int = |scale: f64| (value * scale).round().clamp(-scale, scale - 1.0) as i32;
+        match self {
+            Self::U8 => sample[0] = (int(128.0) + 128) as u8,
+            Self::I16 => sample.copy_from_slice(&(int(32768.0) as i16).to_le_bytes()),
+            Self::I24 => sample.copy_from_slice(&int(8388608.0).to_le_bytes()[..3]),
+            Self::I32 => sample.copy_from_slice(&int(2147483648.0).to_le_bytes()),
+            Self::F32 => sample.copy_from_slice(&(value as f32).to_le_bytes()),
+            Self::F64 => sample.copy_from_slice(&value.to_le_bytes()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Adjust {
+    Keep,
+    Drop(u32),
+    Insert(u32),
+}
+
+pub(crate) struct FillController {
+    target: u32,
+    band: u32,
+    step: u32,
+    seen: u32,
+    peak: u32,
+}
+impl FillController {
+    pub(crate) fn new(target: u32, period: u32) -> Self {
+        Self {
+            target,
+            band: period,
+            step: (period / 8).max(1),
+            seen: 0,
+            peak: 0,
+        }
+    }
+    pub(crate) fn target(&self) -> u32 {
+        self.target
+    }
+    /// Feeds the ring fill seen by one callback, the highest fill over a window is compared to the target.
+    pub(crate) fn update(&mut self, fill: u32) -> Adjust {
+        self.peak = self.peak.max(fill);
+        self.seen += 1;
+        if self.seen < WINDOW {
+            return Adjust::Keep;
+        }
+        let peak = std::mem::take(&mut self.peak);
+        self.seen = 0;
+        if peak > self.target + self.band {
+            Adjust::Drop(self.step.min(peak - self.target))
+        } else if peak + self.band < self.target {
+            Adjust::Insert(self.step.min(self.target - peak))
+        } else {
+            Adjust::Keep
+        }
+    }
+}
+
+pub(crate) struct Splicer {
+    format: SampleFormat,
+    align: usize,
+    fade: usize,
+    scratch: Vec<u8>,
+}
+impl Splicer {
+    pub(crate) fn new(format: SampleFormat, align: usize, fade: u32) -> Self {
+        Self {
+            format,
+            align,
+            fade: fade.max(1) as usize,
+            scratch: Vec::new(),
+        }
+    }
+    /// Frames to hand to the engine for `available` frames in the ring and `room` in the engine,
+    /// the adjustment falls back to `Keep` when there isn't enough data around for the crossfade.
+    pub(crate) fn plan(&self, available: usize, room: usize, adjust: Adjust) -> (usize, Adjust) {
+        let fade = self.fade;
+        match adjust {
+            Adjust::Drop(k) if available >= k as usize + fade && room >= fade => {
+                ((available - k as usize).min(room), adjust)
+            }
+            Adjust::Insert(k)
+                if available.min(room.saturating_sub(k as usize)) >= k as usize + fade =>
+            {
+                (available.min(room - k as usize) + k as usize, adjust)
+            }
+            _ => (available.min(room), Adjust::Keep),
+        }
+    }
+    /// Fills `out` from the ring, skipping or repeating frames through a crossfade as planned.
+    pub(crate) fn pop(
+        &mut self,
+        buffer: &mut Consumer<u8>,
+        out: &mut [u8],
+        adjust: Adjust,
+    ) -> Result<(), impl std::fmt::Display> {
+        let len = out.len();
+        let (consume, skip) = match adjust {
+            Adjust::Keep => return buffer.pop_entire_slice(out),
+            Adjust::Drop(k) => (len + k as usize * self.align, k as usize * self.align),
+            Adjust::Insert(k) => (len - k as usize * self.align, k as usize * self.align),
+        };
+        let chunk = buffer.read_chunk(consume)?;
+        let (first, second) = chunk.as_slices();
+        self.scratch.clear();
+        self.scratch.extend_from_slice(first);
+        self.scratch.extend_from_slice(second);
+        chunk.commit_all();
+        let source = &self.scratch;
+        let fade = self.fade * self.align;
+        match adjust {
+            Adjust::Drop(_) => {
+                self.crossfade(
+                    &mut out[..fade],
+                    &source[..fade],
+                    &source[skip..skip + fade],
+                );
+                out[fade..].copy_from_slice(&source[skip + fade..]);
+            }
+            Adjust::Insert(_) => {
+                out[..skip].copy_from_slice(&source[..skip]);
+                self.crossfade(
+                    &mut out[skip..skip + fade],
+                    &source[skip..skip + fade],
+                    &source[..fade],
+                );
+                out[skip + fade..].copy_from_slice(&source[fade..]);
+            }
+            Adjust::Keep => unreachable!(),
+        }
+        Ok(())
+    }
+    fn crossfade(&self, out: &mut [u8], from: &[u8], to: &[u8]) {
+        let size = self.format.bytes();
+        let frames = out.len() / self.align;
+        for (i, ((out, from), to)) in out
+            .chunks_exact_mut(self.align)
+            .zip(from.chunks_exact(self.align))
+            .zip(to.chunks_exact(self.align))
+            .enumerate()
+        {
+            let weight = 0.5 - 0.5 * (PI * (i as f64 + 0.5) / frames as f64).cos();
+            for ((out, from), to) in out
+                .chunks_exact_mut(size)
+                .zip(from.chunks_exact(size))
+                .zip(to.chunks_exact(size))
+            {
+                let value = self.format.read(from) * (1.0 - weight) + self.format.read(to) * weight;
+                self.format.write(out, value);
+            }
+        }
+    }
+}
+
+pub(crate) struct Concealer {
+    format: SampleFormat,
+    align: usize,
+    periods: usize,
+    last: Vec<u8>,
+    played: usize,
+}
+impl Concealer {
+    pub(crate) fn new(format: SampleFormat, align: usize, period: u32, periods: u32) -> Self {
+        let mut last = vec![0u8; period.max(1) as usize * align];
+        last.chunks_exact_mut(format.bytes())
+            .for_each(|sample| format.write(sample, 0.0));
+        Self {
+            format,
+            align,
+            periods: periods as usize,
+            last,
+            played: 0,
+        }
+    }
+    /// Keeps the tail of what went to the engine, which also ends a running concealment.
+    pub(crate) fn record(&mut self, data: &[u8]) {
+        let len = self.last.len();
+        if data.len() >= len {
+            self.last.copy_from_slice(&data[data.len() - len..]);
+        } else {
+            self.last.rotate_left(data.len());
+            self.last[len - data.len()..].copy_from_slice(data);
+        }
+        self.played = 0;
+    }
+    /// Plays the last period back and forth, starting mirrored so the seams stay continuous,
+    /// fades out over the final period and is silent afterwards.
+    pub(crate) fn conceal(&mut self, out: &mut [u8]) {
+        let size = self.format.bytes();
+        let frames = self.last.len() / self.align;
+        for out in out.chunks_exact_mut(self.align) {
+            let (lap, i) = (self.played / frames, self.played % frames);
+            self.played += 1;
+            if lap >= self.periods {
+                out.chunks_exact_mut(size)
+                    .for_each(|sample| self.format.write(sample, 0.0));
+                continue;
+            }
+            let source = if lap % 2 == 0 { frames - 1 - i } else { i };
+            let gain = if lap + 1 == self.periods {
+                0.5 + 0.5 * (PI * (i as f64 + 0.5) / frames as f64).cos()
+            } else {
+                1.0
+            };
+            let source = &self.last[source * self.align..(source + 1) * self.align];
+            for (out, source) in out.chunks_exact_mut(size).zip(source.chunks_exact(size)) {
+                self.format.write(out, self.format.read(source) * gain);
+            }
+        }
+    }
+}
+
+pub(crate) struct Fader {
+    format: SampleFormat,
+    align: usize,
+    len: u32,
+    level: u32,
+    rising: bool,
+}
+impl Fader {
+    pub(crate) fn new(format: SampleFormat, align: usize, len: u32) -> Self {
+        Self {
+            format,
+            align,
+            len: len.max(1),
+            level: 0,
+            rising: true,
+        }
+    }
+    pub(crate) fn len(&self) -> u32 {
+        self.len
+    }
+    pub(crate) fn fade_in(&mut self) {
+        self.rising = true
+    }
+    pub(crate) fn fade_out(&mut self) {
+        self.rising = false
+    }
+    /// Drops to silence right away, the next data fades in again.
+    pub(crate) fn restart(&mut self) {
+        self.level = 0;
+        self.rising = true;
+    }
+    pub(crate) fn mute(&mut self) {
+        self.level = 0;
+        self.rising = false;
+    }
+    pub(crate) fn silent(&self) -> bool {
+        !self.rising && self.level == 0
+    }
+    /// Frames left before a fade-out reaches silence.
+    pub(crate) fn remaining(&self) -> u32 {
+        if self.rising { u32::MAX } else { self.level }
+    }
+    pub(crate) fn apply(&mut self, data: &mut [u8]) {
+        if self.rising && self.level == self.len {
+            return;
+        }
+        let size = self.format.bytes();
+        for frame in data.chunks_exact_mut(self.align) {
+            if self.rising {
+                self.level = (self.level + 1).min(self.len);
+            } else {
+                self.level = self.level.saturating_sub(1);
+            }
+            let gain = 0.5 - 0.5 * (PI * self.level as f64 / self.len as f64).cos();
+            for sample in frame.chunks_exact_mut(size) {
+                self.format.write(sample, self.format.read(sample) * gain);
+            }
+        }
+    }
+}
+
+pub(crate) struct RingWriter {
+    buffer: Producer<u8>,
+    cache: Box<[u8]>,
+    direct: bool,
+    zero_copy: bool,
+}
+impl RingWriter {
+    pub(crate) fn new(buffer: Producer<u8>, cache: usize) -> Self {
+        Self {
+            buffer,
+            cache: vec![0; cache].into_boxed_slice(),
+            direct: false,
+            zero_copy: true,
+        }
+    }
+    pub(crate) fn producer(&self) -> &Producer<u8> {
+        &self.buffer
+    }
+    /// Points into the ring when `len` bytes fit before the wrap, otherwise into the cache.
+    pub(crate) fn get(&mut self, len: usize) -> *mut u8 {
+        if self.zero_copy
+            && let Ok(mut chunk) = self.buffer.write_chunk_uninit(len)
+        {
+            let (first, _) = chunk.as_mut_slices();
+            if first.len() == len {
+                self.direct = true;
+                return first.as_mut_ptr().cast();
+            }
+        }
+        self.direct = false;
+        self.cache.as_mut_ptr()
+    }
+    pub(crate) fn release(&mut self, len: usize, silent: bool) -> Result<(), ChunkError> {
+        if self.direct {
+            self.buffer.write_chunk_uninit(len).map(|mut chunk| {
+                if silent {
+                    let (first, second) = chunk.as_mut_slices();
+                    first.fill(MaybeUninit::new(0));
+                    second.fill(MaybeUninit::new(0));
+                }
+                unsafe { chunk.commit_all() }
+            })
+        } else {
+            let slice = &mut self.cache[..len];
+            if silent {
+                slice.fill(0);
+            }
+            self.buffer.push_entire_slice(slice)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rtrb::RingBuffer;
+    use std::f64::consts::TAU;
+    use std::hint::black_box;
+    use std::time::Instant;
+
+    const CHANNELS: usize = 2;
+    const PERIOD: u32 = 96;
+    const RING: u32 = PERIOD * 10;
+    const TARGET: u32 = PERIOD * 3;
+    const TONE_STEP: f64 = TAU * 440.0 / 48000.0;
+
+    fn ramp(format: SampleFormat, frames: u32) -> Vec<u8> {
+        let size = format.bytes();
+        let mut data = vec![0u8; frames as usize * CHANNELS * size];
+        for (i, frame) in data.chunks_exact_mut(CHANNELS * size).enumerate() {
+            for sample in frame.chunks_exact_mut(size) {
+                format.write(sample, i as f64 / 1024.0);
+            }
+        }
+        data
+    }
+
+    fn frame_value(format: SampleFormat, data: &[u8], frame: usize) -> f64 {
+        format.read(&data[frame * CHANNELS * format.bytes()..])
+    }
+
+    #[test]
+    fn plan_falls_back_to_keep() {
+        let splicer = Splicer::new(SampleFormat::I16, 4, 8);
+        assert_eq!(splicer.plan(10, 96, Adjust::Drop(4)), (10, Adjust::Keep));
+        assert_eq!(splicer.plan(100, 4, Adjust::Drop(4)), (4, Adjust::Keep));
+        assert_eq!(
+            splicer.plan(100, 96, Adjust::Drop(4)),
+            (96, Adjust::Drop(4))
+        );
+        assert_eq!(splicer.plan(100, 10, Adjust::Insert(4)), (10, Adjust::Keep));
+        assert_eq!(splicer.plan(10, 96, Adjust::Insert(4)), (10, Adjust::Keep));
+        assert_eq!(
+            splicer.plan(100, 96, Adjust::Insert(4)),
+            (96, Adjust::Insert(4))
+        );
+        assert_eq!(
+            splicer.plan(50, 96, Adjust::Insert(4)),
+            (54, Adjust::Insert(4))
+        );
+    }
+
+    #[test]
+    fn pop_drops_and_inserts_frames() {
+        let format = SampleFormat::I16;
+        let align = CHANNELS * format.bytes();
+        let mut splicer = Splicer::new(format, align, 8);
+        for (adjust, consumed) in [
+            (Adjust::Keep, 96),
+            (Adjust::Drop(4), 100),
+            (Adjust::Insert(4), 92),
+        ] {
+            let (mut producer, mut consumer) = RingBuffer::new(200 * align);
+            producer.push_entire_slice(&ramp(format, 200)).unwrap();
+            let (frames, adjust) = splicer.plan(200, 96, adjust);
+            let mut out = vec![0u8; frames * align];
+            assert!(splicer.pop(&mut consumer, &mut out, adjust).is_ok());
+            assert_eq!(frames, 96);
+            assert_eq!(consumer.slots(), (200 - consumed) * align);
+            let last = frame_value(format, &out, frames - 1);
+            assert_eq!(last, frame_value(format, &ramp(format, 200), consumed - 1));
+        }
+    }
+
+    #[test]
+    fn pop_fails_without_data() {
+        let format = SampleFormat::F32;
+        let align = CHANNELS * format.bytes();
+        let mut splicer = Splicer::new(format, align, 8);
+        let (mut producer, mut consumer) = RingBuffer::new(200 * align);
+        producer.push_entire_slice(&ramp(format, 50)).unwrap();
+        let mut out = vec![0u8; 96 * align];
+        assert!(
+            splicer
+                .pop(&mut consumer, &mut out, Adjust::Drop(4))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn controller_holds_inside_the_band() {
+        let mut controller = FillController::new(TARGET, PERIOD);
+        for fill in (TARGET - PERIOD..=TARGET + PERIOD)
+            .cycle()
+            .take(WINDOW as usize * 8)
+        {
+            assert_eq!(controller.update(fill), Adjust::Keep);
+        }
+        let mut window = |fill| {
+            (0..WINDOW - 1).for_each(|_| assert_eq!(controller.update(fill), Adjust::Keep));
+            controller.update(fill)
+        };
+        assert_eq!(window(TARGET + PERIOD), Adjust::Keep);
+        assert_eq!(window(TARGET + PERIOD + 1), Adjust::Drop(PERIOD / 8));
+        assert_eq!(window(RING), Adjust::Drop(PERIOD / 8));
+        assert_eq!(window(TARGET - PERIOD), Adjust::Keep);
+        assert_eq!(window(TARGET - PERIOD - 1), Adjust::Insert(PERIOD / 8));
+    }
+
+    #[test]
+    fn controller_follows_the_peak() {
+        let mut controller = FillController::new(TARGET, PERIOD);
+        for _ in 0..WINDOW - 1 {
+            assert_eq!(controller.update(0), Adjust::Keep);
+        }
+        assert_eq!(controller.update(TARGET), Adjust::Keep);
+    }
+
+    type App = fn(u32, u32) -> u32;
+
+    struct Report {
+        peak: u32,
+        underruns: u32,
+        jump: f64,
+    }
+
+    /// Mirrors the Ringbuf render callback, one engine period is consumed per tick after the app wrote.
+    fn simulate(format: SampleFormat, app: App) -> Report {
+        const TICKS: u32 = 5000;
+        let align = CHANNELS * format.bytes();
+        let (mut producer, mut consumer): (Producer<u8>, _) =
+            RingBuffer::new(RING as usize * align);
+        let mut controller = FillController::new(TARGET, PERIOD);
+        let mut splicer = Splicer::new(format, align, PERIOD / 2);
+        let mut out = vec![0u8; PERIOD as usize * align];
+        let mut phase = 0u64;
+        let mut report = Report {
+            peak: 0,
+            underruns: 0,
+            jump: 0.0,
+        };
+        let mut last: Option<f64> = None;
+        for tick in 0..TICKS {
+            let fill = (RING as usize - producer.slots() / align) as u32;
+            let padding = (fill + RING - TARGET).min(RING);
+            let frames = app(tick, RING - padding).min(producer.slots() as u32 / align as u32);
+            let mut data = vec![0u8; frames as usize * align];
+            for frame in data.chunks_exact_mut(align) {
+                let value = (TONE_STEP * phase as f64).sin() * 0.5;
+                for sample in frame.chunks_exact_mut(format.bytes()) {
+                    format.write(sample, value);
+                }
+                phase += 1;
+            }
+            producer.push_entire_slice(&data).unwrap();
+
+            let fill = consumer.slots() / align;
+            let adjust = controller.update(fill as u32);
+            let (written, adjust) = splicer.plan(fill, PERIOD as usize, adjust);
+            let out = &mut out[..written * align];
+            assert!(splicer.pop(&mut consumer, out, adjust).is_ok());
+            if written < PERIOD as usize && tick > 0 {
+                report.underruns += 1;
+            }
+            for frame in 0..written {
+                let value = frame_value(format, out, frame);
+                if let Some(last) = last {
+                    report.jump = report.jump.max((value - last).abs());
+                }
+                last = Some(value);
+            }
+            if tick >= TICKS - 64 {
+                report.peak = report.peak.max(fill as u32);
+            }
+            if written < PERIOD as usize {
+                last = None;
+            }
+        }
+        report
+    }
+
+    #[test]
+    fn settles_around_the_target() {
+        let patterns: [(&str, App); 5] = [
+            ("fill to the top", |_, room| room),
+            ("prefilled", |tick, _| if tick == 0 { RING } else { PERIOD }),
+            ("fast clock", |tick, _| PERIOD + (tick % 50 == 0) as u32),
+            ("slow clock", |tick, _| match tick {
+                0 => TARGET + PERIOD,
+                _ => PERIOD - (tick % 50 == 0) as u32,
+            }),
+            (
+                "bursts",
+                |tick, _| {
+                    if tick % 4 == 0 { PERIOD * 4 } else { 0 }
+                },
+            ),
+        ];
+        for format in [SampleFormat::I16, SampleFormat::F32] {
+            for (name, app) in patterns {
+                let report = simulate(format, app);
+                assert!(
+                    report.peak.abs_diff(TARGET) <= PERIOD,
+                    "{name}: peak {}",
+                    report.peak
+                );
+                assert_eq!(report.underruns, 0, "{name}");
+                assert!(report.jump < TONE_STEP, "{name}: step {}", report.jump);
+            }
+        }
+    }
+
+    fn write_period(writer: &mut RingWriter, data: &[u8]) {
+        let out = unsafe { std::slice::from_raw_parts_mut(writer.get(data.len()), data.len()) };
+        out.copy_from_slice(data);
+        writer.release(data.len(), false).unwrap();
+    }
+
+    #[test]
+    fn writer_keeps_data_across_the_wrap() {
+        let align = CHANNELS * 4;
+        let (producer, mut consumer) = RingBuffer::new(RING as usize * align);
+        let mut writer = RingWriter::new(producer, RING as usize * align);
+        let (mut direct, mut cached) = (0, 0);
+        for seed in 0..RING as usize {
+            let data = (0..(seed % 7 + 1) * 13 * align)
+                .map(|i| (seed + i) as u8)
+                .collect::<Vec<_>>();
+            write_period(&mut writer, &data);
+            *if writer.direct {
+                &mut direct
+            } else {
+                &mut cached
+            } += 1;
+            let chunk = consumer.read_chunk(data.len()).unwrap();
+            let (first, second) = chunk.as_slices();
+            assert_eq!([first, second].concat(), data, "seed {seed}");
+            chunk.commit_all();
+        }
+        assert!(direct > 0 && cached > 0, "direct {direct}, cached {cached}");
+    }
+
+    fn write_cost(period: usize, zero_copy: bool) -> f64 {
+        const ITERATIONS: usize = 200_000;
+        let data = ramp(SampleFormat::F32, period as u32);
+        let (producer, mut consumer) = RingBuffer::new(RING as usize * CHANNELS * 4);
+        let mut writer = RingWriter::new(producer, RING as usize * CHANNELS * 4);
+        writer.zero_copy = zero_copy;
+        let start = Instant::now();
+        for _ in 0..ITERATIONS {
+            write_period(&mut writer, black_box(&data));
+            let chunk = consumer.read_chunk(consumer.slots()).unwrap();
+            black_box(chunk.as_slices());
+            chunk.commit_all();
+        }
+        start.elapsed().as_nanos() as f64 / ITERATIONS as f64
+    }
+
+    #[test]
+    #[ignore = "benchmark, run with `cargo test --release -- --ignored --nocapture`"]
+    fn ring_write_cost() {
+        for period in [16, 32, 48, 64, 96, 128] {
+            let best = |zero_copy| {
+                (0..5)
+                    .map(|_| write_cost(period, zero_copy))
+                    .fold(f64::MAX, f64::min)
+            };
+            let (cached, direct) = (best(false), best(true));
+            println!(
+                "{period:>4} frames: cached {cached:>6.1}ns, direct {direct:>6.1}ns per period ({:+.1}%)",
+                (direct / cached - 1.0) * 100.0
+            );
+        }
+    }
+}
@@ -1,26 +1,34 @@
 // #[cfg(test)]
 // mod config_test;
+mod config;
+mod dsp;
 
+use config::*;
+use dsp::*;
 use flexi_logger::*;
 use log::*;
 use retour::GenericDetour;
 use rtrb::{Consumer, Producer, RingBuffer};
-use serde::*;
 use std::cell::{Cell, OnceCell, UnsafeCell};
-use std::collections::HashMap;
 use std::mem::transmute;
-use std::num::NonZero;
 use std::os::raw::c_void;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::slice::from_raw_parts_mut;
-use std::sync::{LazyLock, Once, OnceLock, atomic::*};
+use std::sync::{Arc, LazyLock, Mutex, Once, OnceLock, PoisonError, atomic::*};
 
 use windows::{
     Win32::{
+        Devices::FunctionDiscovery::PKEY_Device_FriendlyName,
         Foundation::*,
         Media::Audio::*,
+        Media::KernelStreaming::{KSDATAFORMAT_SUBTYPE_PCM, WAVE_FORMAT_EXTENSIBLE},
+        Media::Multimedia::{KSDATAFORMAT_SUBTYPE_IEEE_FLOAT, WAVE_FORMAT_IEEE_FLOAT},
         System::Com::{StructuredStorage::*, *},
-        System::LibraryLoader::{GetModuleHandleW, GetProcAddress},
+        System::Diagnostics::Debug::RtlCaptureStackBackTrace,
+        System::LibraryLoader::{
+            GET_MODULE_HANDLE_EX_FLAG_FROM_ADDRESS, GET_MODULE_HANDLE_EX_FLAG_UNCHANGED_REFCOUNT,
+            GetModuleFileNameW, GetModuleHandleExW, GetModuleHandleW, GetProcAddress,
+        },
         System::Threading::*,
         UI::Shell::PropertiesSystem::IPropertyStore,
     },
@@ -29,125 +37,6 @@ use windows::{
 
 static LOGGER_HANDLE: OnceLock<LoggerHandle> = OnceLock::new();
 
-#[derive(Debug, Default, Serialize, Deserialize, PartialEq, Eq, Clone, Copy)]
-enum ConfigLogLevel {
-    Trace,
-    Debug,
-    #[default]
-    Info,
-    Warn,
-    Error,
-    Never,
-}
-
-impl From<ConfigLogLevel> for LevelFilter {
-    fn from(value: ConfigLogLevel) -> Self {
-        match value {
-            ConfigLogLevel::Trace => Self::Trace,
-            ConfigLogLevel::Debug => Self::Debug,
-            ConfigLogLevel::Info => Self::Info,
-            ConfigLogLevel::Warn => Self::Warn,
-            ConfigLogLevel::Error => Self::Error,
-            ConfigLogLevel::Never => Self::Off,
-        }
-    }
-}
-
-#[derive(Debug, Default)]
-enum ConfigSource {
-    #[default]
-    Success,
-    NoParse,
-    NoFile,
-}
-
-#[derive(Debug, Serialize, Deserialize, Default)]
-#[serde(default)]
-struct RedirectConfig {
-    log_path: Option<Box<Path>>,
-    log_level: ConfigLogLevel,
-    only_log_stdout: bool,
-    playback: ClientConfig,
-    capture: ClientConfig,
-    #[serde(skip)]
-    source: ConfigSource,
-}
-impl RedirectConfig {
-    fn load() -> Self {
-        std::fs::read_to_string("redirect_config.toml").map_or_else(
-            |_| Self::new_with_source(ConfigSource::NoFile),
-            |str| {
-                toml::from_str::<RedirectConfig>(&str)
-                    .unwrap_or_else(|_| Self::new_with_source(ConfigSource::NoParse))
-            },
-        )
-    }
-    fn new_with_source(source: ConfigSource) -> Self {
-        Self {
-            source,
-            ..Self::default()
-        }
-    }
-    #[inline]
-    fn get(&self, dataflow: DeviceDataFlow) -> &ClientConfig {
-        match dataflow {
-            DeviceDataFlow::Capture => &self.capture,
-            DeviceDataFlow::Playback => &self.playback,
-        }
-    }
-}
-
-#[derive(Debug, Serialize, Deserialize, Default)]
-#[serde(default)]
-struct ClientConfig {
-    target_period_hus: u32,
-    ring_buffer_len: HashMap<u32, NonZero<u32>>,
-    target_buffer_len: HashMap<u32, NonZero<u32>>,
-    compat_buffer_dur_hns: HashMap<u32, i64>,
-    force_period: bool,
-    mode: ClientMode,
-    raw: bool,
-}
-impl ClientConfig {
-    fn target_buf_len(&self, info: &Shared3Info) -> Option<u32> {
-        self.target_buffer_len
-            .get(&info.samplerate)
-            .map(|l| l.get().next_multiple_of(info.fundamental))
-    }
-    fn ring_buf_len(&self, info: &Shared3Info) -> Option<u32> {
-        self.ring_buffer_len.get(&info.samplerate).map(|l| {
-            info.current_period
-                .max(l.get().next_multiple_of(info.fundamental))
-        })
-    }
-    fn compat_buf_len(&self, info: &Shared3Info) -> Option<i64> {
-        self.compat_buffer_dur_hns.get(&info.samplerate).copied()
-    }
-}
-
-#[derive(Debug, Serialize, Deserialize, Default, PartialEq, Eq)]
-enum ClientMode {
-    #[default]
-    Normal,
-    Compat,
-    Ringbuf,
-    Bypass,
-}
-impl std::fmt::Display for ClientMode {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "{}",
-            match self {
-                Self::Normal => "normal",
-                Self::Compat => "compat",
-                Self::Ringbuf => "ringbuf",
-                Self::Bypass => "bypass",
-            }
-        )
-    }
-}
-
 #[derive(Clone, Copy)]
 enum AudioAlign {
     Pow2(usize),
@@ -178,6 +67,30 @@ impl AudioAlign {
     }
 }
 
+fn sample_format(pformat: *const WAVEFORMATEX) -> Option<SampleFormat> {
+    let format = unsafe { &*pformat };
+    let float = match format.wFormatTag as u32 {
+        WAVE_FORMAT_PCM => false,
+        WAVE_FORMAT_IEEE_FLOAT => true,
+        WAVE_FORMAT_EXTENSIBLE => {
+            let format = unsafe { &*(pformat as *const WAVEFORMATEXTENSIBLE) };
+            match format.SubFormat {
+                KSDATAFORMAT_SUBTYPE_PCM => false,
+                KSDATAFORMAT_SUBTYPE_IEEE_FLOAT => true,
+                _ => return None,
+            }
+        }
+        _ => return None,
+    };
+    SampleFormat::new(float, format.wBitsPerSample)
+}
+
+fn wait_frames(frames: u32, samplerate: u32) {
+    std::thread::sleep(std::time::Duration::from_nanos(
+        frames as u64 * 1_000_000_000 / samplerate.max(1) as u64,
+    ))
+}
+
 macro_rules! trace_tagged {
     (@$self:ident, $($arg:tt)+) => { trace_tagged!($self.info.tag, $($arg)+) };
     ($tag:expr, $($arg:tt)+) => { trace!(target: $tag.as_ref(), $($arg)+) };
@@ -199,7 +112,104 @@ macro_rules! error_tagged {
     ($tag:expr, $($arg:tt)+) => { error!(target: $tag.as_ref(), $($arg)+) };
 }
 
-static CONFIG: LazyLock<RedirectConfig> = LazyLock::new(RedirectConfig::load);
+static CONFIG: LazyLock<Arc<RedirectConfig>> =
+    LazyLock::new(|| RedirectConfig::load(module_dir().as_deref()).into());
+
+static LIVE_CONFIG: LazyLock<Mutex<(Arc<RedirectConfig>, ConfigStamp)>> =
+    LazyLock::new(|| Mutex::new((CONFIG.clone(), CONFIG.stamp.clone())));
+
+fn live_config() -> Arc<RedirectConfig> {
+    if !CONFIG.hot_reload {
+        return CONFIG.clone();
+    }
+    let mut live = LIVE_CONFIG.lock().unwrap_or_else(PoisonError::into_inner);
+    let (config, stamp) = &mut *live;
+    if stamp.is_stale() {
+        let mut reloaded = RedirectConfig::load(module_dir().as_deref());
+        *stamp = std::mem::take(&mut reloaded.stamp);
+        match &reloaded.source {
+            ConfigSource::NoParse(e) => {
+                warn!("Unable to reload config, keeping the previous one, error: {e}")
+            }
+            ConfigSource::NoFile => {
+                warn!("Config file not found on reload, keeping the previous one")
+            }
+            ConfigSource::Success(path) => {
+                info!(
+                    "Config reloaded from {}, applying to new clients",
+                    path.display()
+                );
+                for warning in &reloaded.warnings {
+                    warn!("{warning}")
+                }
+                *config = reloaded.into();
+            }
+        }
+    }
+    config.clone()
+}
+
+static MODULE: AtomicPtr<c_void> = AtomicPtr::new(std::ptr::null_mut());
+
+fn module_dir() -> Option<PathBuf> {
+    let module = MODULE.load(Ordering::Relaxed);
+    if module.is_null() {
+        return None;
+    }
+    module_path(HMODULE(module)).and_then(|path| path.parent().map(Path::to_path_buf))
+}
+
+fn module_path(module: HMODULE) -> Option<PathBuf> {
+    let mut buf = vec![0u16; 1024];
+    let len = unsafe { GetModuleFileNameW(Some(module), &mut buf) } as usize;
+    (len != 0 && len < buf.len()).then(|| PathBuf::from(String::from_utf16_lossy(&buf[..len])))
+}
+
+#[inline(never)]
+fn caller_module() -> Option<String> {
+    let mut frames = [std::ptr::null_mut(); 16];
+    let captured = unsafe { RtlCaptureStackBackTrace(1, &mut frames, None) } as usize;
+    let own = MODULE.load(Ordering::Relaxed);
+    frames[..captured].iter().find_map(|&frame| {
+        let mut module = HMODULE::default();
+        unsafe {
+            GetModuleHandleExW(
+                GET_MODULE_HANDLE_EX_FLAG_FROM_ADDRESS
+                    | GET_MODULE_HANDLE_EX_FLAG_UNCHANGED_REFCOUNT,
+                PCWSTR(frame.cast()),
+                &mut module,
+            )
+            .ok()?
+        };
+        if module.0 == own {
+            return None;
+        }
+        let name = module_path(module)?.file_name()?.to_str()?.to_owned();
+        (!["combase.dll", "ole32.dll"]
+            .iter()
+            .any(|runtime| name.eq_ignore_ascii_case(runtime)))
+        .then_some(name)
+    })
+}
+
+fn should_wrap(api: &str) -> bool {
+    let thread = unsafe {
+        GetThreadDescription(GetCurrentThread())
+            .ok()
+            .filter(|desc| !desc.is_empty())
+            .and_then(|desc| desc.to_string().ok())
+    };
+    let module = caller_module();
+    let (wrap, reason) = CONFIG
+        .filter
+        .should_wrap(thread.as_deref(), module.as_deref());
+    if wrap {
+        debug!("Intercepted IMMDeviceEnumerator creation via {api}, {reason}");
+    } else {
+        debug!("Skipping IMMDeviceEnumerator creation via {api}, {reason}");
+    }
+    wrap
+}
 
 static CLIENT_ID: (AtomicU16, AtomicU16) = (AtomicU16::new(0), AtomicU16::new(0));
 
@@ -220,8 +230,6 @@ type FnCoCreateInstanceEx = unsafe extern "system" fn(
     *mut MULTI_QI,
 ) -> HRESULT;
 
-const KEYWORDS: &[&str] = &["[GAME]", "[SK]"];
-
 static CO_CREATE: LazyLock<(
     GenericDetour<FnCoCreateInstance>,
     GenericDetour<FnCoCreateInstanceEx>,
@@ -260,14 +268,7 @@ unsafe extern "system" fn hooked_cocreateinstance(
         let ret = CO_CREATE.0.call(rclsid, punkouter, dwclscontext, riid, ppv);
         if *riid == IMMDeviceEnumerator::IID && ret.is_ok() {
             LOGGER_HANDLE.get_or_init(setup);
-            if let Ok(thread_desc) = GetThreadDescription(GetCurrentThread())
-                && !thread_desc.is_empty()
-                && let Ok(name) = thread_desc.to_string()
-                && KEYWORDS.iter().any(|keyword| name.contains(keyword))
-            {
-                trace!("Skipping SpecialK CoCreateInstance calls, thread name: {name}");
-            } else {
-                debug!("Intercepted IMMDeviceEnumerator creation via CoCreateInstance");
+            if should_wrap("CoCreateInstance") {
                 let proxy_enumerator: IMMDeviceEnumerator =
                     RedirectDeviceEnumerator::new(IMMDeviceEnumerator::from_raw(*ppv)).into();
                 *ppv = proxy_enumerator.into_raw();
@@ -291,16 +292,9 @@ unsafe extern "system" fn hooked_cocreateinstanceex(
             .call(clsid, punkouter, dwclsctx, pserverinfo, dwcount, presults);
         if *clsid == MMDeviceEnumerator && hr.is_ok() {
             LOGGER_HANDLE.get_or_init(setup);
-            if let Ok(thread_desc) = GetThreadDescription(GetCurrentThread())
-                && !thread_desc.is_empty()
-                && let Ok(name) = thread_desc.to_string()
-                && KEYWORDS.iter().any(|keyword| name.contains(keyword))
-            {
-                trace!("Skipping SpecialK CoCreateInstanceEx calls, thread name: {name}")
-            } else {
+            if should_wrap("CoCreateInstanceEx") {
                 for qi in from_raw_parts_mut(presults, dwcount as usize) {
                     if *qi.pIID == IMMDeviceEnumerator::IID && qi.hr.is_ok() {
-                        debug!("Intercepted IMMDeviceEnumerator via CoCreateInstanceEx");
                         let proxy_enumerator: IMMDeviceEnumerator =
                             RedirectDeviceEnumerator::new(IMMDeviceEnumerator::from_raw(
                                 qi.pItf.take().unwrap_unchecked().into_raw(),
@@ -346,10 +340,11 @@ impl IMMDeviceEnumerator_Impl for RedirectDeviceEnumerator_Impl {
             "DeviceEnumerator::GetDefaultAudioEndpoint requested on flow {}",
             dataflow.0
         );
-        Ok(
-            RedirectDevice::new(unsafe { self.inner.GetDefaultAudioEndpoint(dataflow, role)? })
-                .into(),
+        Ok(RedirectDevice::with_role(
+            unsafe { self.inner.GetDefaultAudioEndpoint(dataflow, role)? },
+            role,
         )
+        .into())
     }
 
     fn GetDevice(&self, pwstrid: &PCWSTR) -> WinResult<IMMDevice> {
@@ -417,7 +412,11 @@ macro_rules! impl_boilerplate {
         }
         fn GetStreamLatency(&self) -> WinResult<i64> {
             info_tagged!(@self, "GetStreamLatency called");
-            unsafe { self.inner.GetStreamLatency() }
+            if self.info.initialized() {
+                Ok(self.latency()?.hns())
+            } else {
+                unsafe { self.inner.GetStreamLatency() }
+            }
         }
     };
     (IAudioClient2) =>
@@ -488,16 +487,42 @@ macro_rules! drop_boilerplate {
     };
 }
 
-#[repr(transparent)]
 #[implement(IMMDevice, IMMEndpoint)]
 struct RedirectDevice {
     inner: IMMDevice,
+    role: Option<StreamRole>,
 }
 
 impl RedirectDevice {
     pub fn new(inner: IMMDevice) -> Self {
-        Self { inner }
+        Self { inner, role: None }
+    }
+    pub fn with_role(inner: IMMDevice, role: ERole) -> Self {
+        Self {
+            inner,
+            role: StreamRole::from_raw(role.0),
+        }
+    }
+    fn names(&self) -> Vec<String> {
+        endpoint_names(&self.inner)
+    }
+}
+
+fn endpoint_names(device: &IMMDevice) -> Vec<String> {
+    let mut names = Vec::with_capacity(2);
+    unsafe {
+        if let Ok(id) = device.GetId() {
+            names.extend(id.to_string().ok());
+            CoTaskMemFree(Some(id.0.cast()));
+        }
+        if let Ok(name) = device
+            .OpenPropertyStore(STGM_READ)
+            .and_then(|store| store.GetValue(&PKEY_Device_FriendlyName))
+        {
+            names.push(name.to_string());
+        }
     }
+    names
 }
 
 impl IMMDevice_Impl for RedirectDevice_Impl {
@@ -517,47 +542,61 @@ impl IMMDevice_Impl for RedirectDevice_Impl {
                         .inner
                         .Activate::<IAudioClient3>(dwclsctx, Some(pactivationparams))?;
                     let dataflow = self.inner.cast::<IMMEndpoint>()?.GetDataFlow()?.into();
-                    let config = CONFIG.get(dataflow);
-                    let tag = format!(
-                        "{dataflow}-{}::{}",
-                        match dataflow {
-                            DeviceDataFlow::Playback => CLIENT_ID.0.fetch_add(1, Ordering::Relaxed),
-                            DeviceDataFlow::Capture => CLIENT_ID.1.fetch_add(1, Ordering::Relaxed),
-                        },
-                        config.mode
-                    );
-                    info_tagged!(tag, "Client created");
-                    let info = RedirectClientInfo::new(config, tag.into());
-                    let proxy: IAudioClient3 = match config.mode {
-                        ClientMode::Normal => RedirectAudioClient::new(inner, info).into(),
-                        ClientMode::Compat => RedirectCompatAudioClient::new(
+                    let live = live_config();
+                    let device = if live.device.is_empty() {
+                        None
+                    } else {
+                        live.device_overlay(&self.names())
+                    };
+                    let id = match dataflow {
+                        DeviceDataFlow::Playback => CLIENT_ID.0.fetch_add(1, Ordering::Relaxed),
+                        DeviceDataFlow::Capture => CLIENT_ID.1.fetch_add(1, Ordering::Relaxed),
+                    };
+                    let client = live.client_overlay(dataflow, id);
+                    let proxy = if live.rules.is_empty() {
+                        let overlays = device
+                            .map(|(_, overlay)| overlay)
+                            .into_iter()
+                            .chain(client)
+                            .collect::<Vec<_>>();
+                        let config = live.client_config(dataflow, &overlays);
+                        let tag = format!("{dataflow}-{id}::{}", config.mode);
+                        info_tagged!(tag, "Client created");
+                        if let Some((pattern, _)) = device {
+                            info_tagged!(tag, "Using device config: {pattern}");
+                        }
+                        if client.is_some() {
+                            info_tagged!(tag, "Using client config: {dataflow}.client.{id}");
+                        }
+                        create_client(
+                            dataflow,
                             inner,
-                            self.inner
-                                .Activate::<IAudioClient3>(dwclsctx, Some(pactivationparams))?,
-                            info,
-                        )
-                        .into(),
-                        ClientMode::Ringbuf => match dataflow {
-                            DeviceDataFlow::Playback => {
-                                RedirectRingbufAudioClient::new(inner, info).into()
-                            }
-                            DeviceDataFlow::Capture => {
-                                warn_tagged!(
-                                    info.tag,
-                                    "Ringbuf mode doesn't work with capture client, switching to compat mode"
-                                );
-                                RedirectCompatAudioClient::new(
-                                    inner,
-                                    self.inner.Activate::<IAudioClient3>(
-                                        dwclsctx,
-                                        Some(pactivationparams),
-                                    )?,
-                                    info,
-                                )
-                                .into()
-                            }
-                        },
-                        ClientMode::Bypass => inner,
+                            || {
+                                self.inner
+                                    .Activate::<IAudioClient3>(dwclsctx, Some(pactivationparams))
+                            },
+                            RedirectClientInfo::new(config, tag.into()),
+                        )?
+                    } else {
+                        let tag = format!("{dataflow}-{id}::deferred");
+                        info_tagged!(tag, "Client created, rules are evaluated on Initialize");
+                        if let Some((pattern, _)) = device {
+                            info_tagged!(tag, "Using device config: {pattern}");
+                        }
+                        if client.is_some() {
+                            info_tagged!(tag, "Using client config: {dataflow}.client.{id}");
+                        }
+                        let pattern = device.map(|(pattern, _)| pattern.into());
+                        RedirectDeferredAudioClient::new(
+                            inner,
+                            self.inner.clone(),
+                            (dwclsctx, pactivationparams.as_ref().cloned()),
+                            (dataflow, self.role, id),
+                            pattern,
+                            live.clone(),
+                            tag.into(),
+                        )?
+                        .into()
                     };
                     proxy.query(riid, ppinterface).ok()
                 }
@@ -595,11 +634,20 @@ impl IMMEndpoint_Impl for RedirectDevice_Impl {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
-enum DeviceDataFlow {
-    Capture,
-    Playback,
+fn create_client(
+    dataflow: DeviceDataFlow,
+    inner: IAudioClient3,
+    hooker: impl FnOnce() -> WinResult<IAudioClient3>,
+    info: RedirectClientInfo,
+) -> WinResult<IAudioClient3> {
+    Ok(match info.config.mode {
+        ClientMode::Normal => RedirectAudioClient::new(inner, info).into(),
+        ClientMode::Compat => RedirectCompatAudioClient::new(inner, hooker()?, info).into(),
+        ClientMode::Ringbuf => RedirectRingbufAudioClient::new(dataflow, inner, info).into(),
+        ClientMode::Bypass => inner,
+    })
 }
+
 impl From<EDataFlow> for DeviceDataFlow {
     fn from(value: EDataFlow) -> Self {
         match value.0 {
@@ -609,32 +657,7 @@ impl From<EDataFlow> for DeviceDataFlow {
         }
     }
 }
-impl std::fmt::Display for DeviceDataFlow {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "{}",
-            match self {
-                Self::Capture => "capture",
-                Self::Playback => "playback",
-            }
-        )
-    }
-}
-
-const fn calculate_buffer(sample_rate: u32, fundamental: u32, target: u32) -> u32 {
-    sample_rate * target / 10000 / fundamental * fundamental
-}
-
-const fn calculate_period(sample_rate: u32, buffer_len: u32) -> i64 {
-    (buffer_len * 100000 / (sample_rate / 100)) as i64
-}
 
-struct Shared3Info {
-    current_period: u32,
-    samplerate: u32,
-    fundamental: u32,
-}
 impl Shared3Info {
     fn init(inner: &IAudioClient3, config: &ClientConfig, tag: &str) -> WinResult<Self> {
         let mut periods = [0; 4];
@@ -650,52 +673,143 @@ impl Shared3Info {
         };
         let samplerate = unsafe { *pformat }.nSamplesPerSec;
         unsafe { CoTaskMemFree(Some(pformat.cast())) };
-        let current_period = if config.target_period_hus != 0 {
-            calculate_buffer(samplerate, periods[1], config.target_period_hus)
-                .clamp(periods[2], periods[3])
-        } else {
-            periods[2]
-        };
+        let info = Self::new(config, samplerate, periods[1], (periods[2], periods[3]));
         info_tagged!(
             tag,
-            "Period: Current = {current_period}, Min = {}, Max = {}, Samplerate = {samplerate}",
+            "Period: Current = {}, Min = {}, Max = {}, Samplerate = {samplerate}",
+            info.current_period,
             periods[2],
             periods[3]
         );
-        Ok(Self {
-            current_period,
-            samplerate,
-            fundamental: periods[1],
-        })
+        for (key, rate) in &info.fallbacks {
+            info_tagged!(
+                tag,
+                "Samplerate {samplerate} not configured in {key}, using {rate}"
+            );
+        }
+        Ok(info)
     }
 }
 
 struct RedirectClientInfo {
     parameters: OnceCell<WinResult<Shared3Info>>,
     raw_flag: Once,
-    config: &'static ClientConfig,
+    config: ClientConfig,
     tag: Box<str>,
     initialized: Cell<bool>,
+    samplerate: Cell<u32>,
+    format: Cell<Option<SampleFormat>>,
+    offset: Arc<AtomicI64>,
+    latency_logged: Once,
 }
 impl RedirectClientInfo {
-    fn new(config: &'static ClientConfig, tag: Box<str>) -> Self {
+    fn new(config: ClientConfig, tag: Box<str>) -> Self {
         Self {
             parameters: OnceCell::new(),
             raw_flag: Once::new(),
             config,
             tag,
             initialized: false.into(),
+            samplerate: 0.into(),
+            format: None.into(),
+            offset: Arc::new(0.into()),
+            latency_logged: Once::new(),
+        }
+    }
+    fn initialize(&self, pformat: *const WAVEFORMATEX) {
+        self.samplerate.set(unsafe { (*pformat).nSamplesPerSec });
+        self.format.set(sample_format(pformat));
+        self.initialized.set(true);
+    }
+    fn fader(&self, align: usize) -> Option<Fader> {
+        let len = self.config.fade_frames(self.samplerate.get());
+        if len == 0 {
+            return None;
+        }
+        let Some(format) = self.format.get() else {
+            warn_tagged!(self.tag, "Unsupported sample format, fades disabled");
+            return None;
+        };
+        info_tagged!(self.tag, "Fade length: {len} frames");
+        Some(Fader::new(format, align, len))
+    }
+    fn clock(
+        &self,
+        inner: &IAudioClient3,
+        riid: *const GUID,
+        ppv: *mut *mut c_void,
+    ) -> WinResult<()> {
+        let clock: IAudioClock = RedirectAudioClock {
+            inner: unsafe { inner.GetService::<IAudioClock>()? },
+            offset: self.offset.clone(),
+            rates: (self.samplerate.get(), self.param(inner)?.samplerate),
+            tag: format!("{}-clock", self.tag).into(),
         }
+        .into();
+        unsafe { clock.query(riid, ppv).ok() }
     }
     fn param(&self, inner: &IAudioClient3) -> WinResult<&Shared3Info> {
         self.parameters
-            .get_or_init(|| Shared3Info::init(inner, self.config, &self.tag))
+            .get_or_init(|| Shared3Info::init(inner, &self.config, &self.tag))
             .as_ref()
             .map_err(|e| e.clone())
     }
     fn initialized(&self) -> bool {
         self.initialized.get()
     }
+    fn engine_len(&self, inner: &IAudioClient3) -> WinResult<u32> {
+        let real_size = unsafe { inner.GetBufferSize()? };
+        let param = self.param(inner)?;
+        Ok(param
+            .target_buf_len()
+            .map_or(real_size, |len| len.clamp(param.current_period, real_size)))
+    }
+    fn log_latency(&self, latency: impl FnOnce() -> WinResult<StreamLatency>) {
+        if self.initialized() {
+            self.latency_logged.call_once(|| match latency() {
+                Ok(latency) => info_tagged!(self.tag, "Latency: {latency}"),
+                Err(e) => warn_tagged!(self.tag, "Unable to calculate latency: {e}"),
+            })
+        }
+    }
+}
+
+struct StreamLatency {
+    samplerate: u32,
+    period: u32,
+    buffer: u32,
+    extra: Option<(&'static str, u32)>,
+}
+impl StreamLatency {
+    fn new(param: &Shared3Info, buffer: u32, extra: Option<(&'static str, u32)>) -> Self {
+        Self {
+            samplerate: param.samplerate,
+            period: param.current_period,
+            buffer,
+            extra,
+        }
+    }
+    fn frames(&self) -> u32 {
+        self.period + self.buffer + self.extra.map_or(0, |(_, frames)| frames)
+    }
+    fn hns(&self) -> i64 {
+        (self.frames() as u64 * 10_000_000 / self.samplerate as u64) as i64
+    }
+}
+impl std::fmt::Display for StreamLatency {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:.3}ms = period {} + engine buffer {}",
+            self.hns() as f64 / 10000.0,
+            self.period,
+            self.buffer
+        )?;
+        if let Some((name, frames)) = self.extra {
+            write!(f, " + {name} {frames}")?;
+        }
+        write!(f, " frames")
+    }
 }
 
 #[implement(IAudioClient3)]
@@ -708,6 +822,14 @@ impl RedirectAudioClient {
     fn new(inner: IAudioClient3, info: RedirectClientInfo) -> Self {
         Self { inner, info }
     }
+    fn latency(&self) -> WinResult<StreamLatency> {
+        let param = self.info.param(&self.inner)?;
+        Ok(StreamLatency::new(
+            param,
+            self.info.engine_len(&self.inner)?,
+            None,
+        ))
+    }
 }
 impl IAudioClient_Impl for RedirectAudioClient_Impl {
     impl_boilerplate!(IAudioClient1);
@@ -779,13 +901,7 @@ impl IAudioClient_Impl for RedirectAudioClient_Impl {
 
     fn GetBufferSize(&self) -> WinResult<u32> {
         if self.info.initialized() {
-            let real_size = unsafe { self.inner.GetBufferSize()? };
-            let param = self.info.param(&self.inner)?;
-            let buf = self
-                .info
-                .config
-                .target_buf_len(param)
-                .map_or(real_size, |len| len.clamp(param.current_period, real_size));
+            let buf = self.info.engine_len(&self.inner)?;
             info_tagged!(@self, "GetBufferSize called, buffer length: {buf}");
             Ok(buf)
         } else {
@@ -800,6 +916,7 @@ impl IAudioClient_Impl for RedirectAudioClient_Impl {
 
     fn Start(&self) -> WinResult<()> {
         info_tagged!(@self, "Start called");
+        self.info.log_latency(|| self.latency());
         unsafe { self.inner.Start() }
     }
 
@@ -819,10 +936,20 @@ impl IAudioClient_Impl for RedirectAudioClient_Impl {
     }
 
     fn GetService(&self, riid: *const GUID, ppv: *mut *mut c_void) -> WinResult<()> {
-        debug_tagged!(@self, "GetService called, iid: {:?}", unsafe { *riid });
-        unsafe {
-            (self.inner.cast::<IAudioClient>()?.vtable().GetService)(self.inner.as_raw(), riid, ppv)
+        let iid = unsafe { *riid };
+        debug_tagged!(@self, "GetService called, iid: {iid:?}");
+        match iid {
+            IAudioClock::IID | IAudioClock2::IID if self.info.initialized() => {
+                self.info.clock(&self.inner, riid, ppv)
+            }
+            _ => unsafe {
+                (self.inner.cast::<IAudioClient>()?.vtable().GetService)(
+                    self.inner.as_raw(),
+                    riid,
+                    ppv,
+                )
                 .ok()
+            },
         }
     }
 }
@@ -861,7 +988,7 @@ impl IAudioClient3_Impl for RedirectAudioClient_Impl {
                 "InitializeSharedAudioStream -> replacing period, current period: {periodinframes}"
             );
         }
-        self.info.initialized.set(true);
+        self.info.initialize(pformat);
         unsafe {
             if self.info.config.raw && !self.info.raw_flag.is_completed() {
                 info_tagged!(@self, "Applying raw flag");
@@ -884,28 +1011,206 @@ impl IAudioClient3_Impl for RedirectAudioClient_Impl {
 
 drop_boilerplate!(RedirectAudioClient);
 
+#[implement(IAudioClock, IAudioClock2)]
+struct RedirectAudioClock {
+    inner: IAudioClock,
+    offset: Arc<AtomicI64>,
+    rates: (u32, u32),
+    tag: Box<str>,
+}
+impl RedirectAudioClock {
+    fn shift(&self, position: u64, rate: u64) -> u64 {
+        let offset = self.offset.load(Ordering::Relaxed);
+        if offset == 0 || self.rates.0 == 0 {
+            return position;
+        }
+        let shift = offset * rate as i64 / self.rates.0 as i64;
+        position.saturating_add_signed(shift)
+    }
+}
+impl IAudioClock_Impl for RedirectAudioClock_Impl {
+    fn GetFrequency(&self) -> WinResult<u64> {
+        debug_tagged!(self.tag, "GetFrequency called");
+        unsafe { self.inner.GetFrequency() }
+    }
+    fn GetPosition(&self, pu64position: *mut u64, pu64qpcposition: *mut u64) -> WinResult<()> {
+        let frequency = unsafe { self.inner.GetFrequency()? };
+        let mut position = 0;
+        unsafe {
+            self.inner
+                .GetPosition(&mut position, Some(pu64qpcposition))?
+        };
+        let shifted = self.shift(position, frequency);
+        trace_tagged!(
+            self.tag,
+            "GetPosition called, inner: {position}, reported: {shifted}"
+        );
+        unsafe { *pu64position = shifted }
+        Ok(())
+    }
+    fn GetCharacteristics(&self) -> WinResult<u32> {
+        debug_tagged!(self.tag, "GetCharacteristics called");
+        unsafe { self.inner.GetCharacteristics() }
+    }
+}
+impl IAudioClock2_Impl for RedirectAudioClock_Impl {
+    fn GetDevicePosition(&self, deviceposition: *mut u64, qpcposition: *mut u64) -> WinResult<()> {
+        let mut position = 0;
+        unsafe {
+            self.inner
+                .cast::<IAudioClock2>()?
+                .GetDevicePosition(&mut position, Some(qpcposition))?
+        };
+        let shifted = self.shift(position, self.rates.1 as u64);
+        trace_tagged!(
+            self.tag,
+            "GetDevicePosition called, inner: {position}, reported: {shifted}"
+        );
+        unsafe { *deviceposition = shifted }
+        Ok(())
+    }
+}
+
 #[implement(IAudioClient3)]
-struct RedirectCompatAudioClient {
+struct RedirectDeferredAudioClient {
     inner: IAudioClient3,
-    hooker: IAudioClient3,
-    info: RedirectClientInfo,
-    outer: OnceCell<IAudioRenderClient>,
-    align: Cell<u16>,
+    provisional: IAudioClient3,
+    device: IMMDevice,
+    activation: (CLSCTX, Option<PROPVARIANT>),
+    stream: (DeviceDataFlow, Option<StreamRole>, u16),
+    pattern: Option<Box<str>>,
+    config: Arc<RedirectConfig>,
+    properties: Cell<Option<AudioClientProperties>>,
+    resolved: OnceCell<IAudioClient3>,
+    tag: Box<str>,
 }
 
-impl RedirectCompatAudioClient {
-    fn new(inner: IAudioClient3, hooker: IAudioClient3, info: RedirectClientInfo) -> Self {
-        Self {
+impl RedirectDeferredAudioClient {
+    fn new(
+        inner: IAudioClient3,
+        device: IMMDevice,
+        activation: (CLSCTX, Option<PROPVARIANT>),
+        stream: (DeviceDataFlow, Option<StreamRole>, u16),
+        pattern: Option<Box<str>>,
+        config: Arc<RedirectConfig>,
+        tag: Box<str>,
+    ) -> WinResult<Self> {
+        let (flow, _, id) = stream;
+        let overlays = pattern
+            .as_ref()
+            .and_then(|pattern| config.device.get(pattern))
+            .into_iter()
+            .chain(config.client_overlay(flow, id))
+            .collect::<Vec<_>>();
+        let base = config.client_config(flow, &overlays);
+        let base_tag = format!("{flow}-{id}::{}", base.mode);
+        let provisional = create_client(
+            flow,
+            inner.clone(),
+            || {
+                let (clsctx, params) = &activation;
+                unsafe {
+                    device
+                        .Activate::<IAudioClient3>(*clsctx, params.as_ref().map(|p| p as *const _))
+                }
+            },
+            RedirectClientInfo::new(base, base_tag.into()),
+        )?;
+        Ok(Self {
             inner,
-            hooker,
-            info,
-            outer: OnceCell::new(),
-            align: 0.into(),
+            provisional,
+            device,
+            activation,
+            stream,
+            pattern,
+            config,
+            properties: Cell::new(None),
+            resolved: OnceCell::new(),
+            tag,
+        })
+    }
+    #[inline]
+    fn client(&self) -> &IAudioClient3 {
+        self.resolved.get().unwrap_or(&self.provisional)
+    }
+    fn initialize(
+        &self,
+        streamflags: u32,
+        pformat: *const WAVEFORMATEX,
+        audiosessionguid: *const GUID,
+        init: impl FnOnce(&IAudioClient3) -> WinResult<()>,
+    ) -> WinResult<()> {
+        if let Some(client) = self.resolved.get() {
+            return init(client);
+        }
+        let client = self.resolve(streamflags, pformat, audiosessionguid)?;
+        if let Err(e) = init(&client) {
+            debug_tagged!(
+                self.tag,
+                "Initialize failed, rules are evaluated again on retry"
+            );
+            return Err(e);
+        }
+        _ = self.resolved.set(client);
+        Ok(())
+    }
+    fn resolve(
+        &self,
+        streamflags: u32,
+        pformat: *const WAVEFORMATEX,
+        audiosessionguid: *const GUID,
+    ) -> WinResult<IAudioClient3> {
+        let (flow, role, id) = self.stream;
+        let format = unsafe { pformat.as_ref() };
+        let stream = StreamInfo {
+            flow,
+            role,
+            category: self
+                .properties
+                .get()
+                .and_then(|properties| StreamCategory::from_raw(properties.eCategory.0)),
+            channels: format.map_or(0, |format| format.nChannels),
+            samplerate: format.map_or(0, |format| format.nSamplesPerSec),
+            stream_flags: streamflags,
+            session: unsafe { audiosessionguid.as_ref() }
+                .map_or_else(Default::default, |guid| format!("{guid:?}").into()),
+        };
+        let Some((name, rule)) = self.config.rule(&stream) else {
+            debug_tagged!(self.tag, "Stream: {stream:?}");
+            info_tagged!(self.tag, "No rule matched, keeping the base config");
+            return Ok(self.provisional.clone());
+        };
+        let overlays = self
+            .pattern
+            .as_ref()
+            .and_then(|pattern| self.config.device.get(pattern))
+            .into_iter()
+            .chain(self.config.client_overlay(flow, id))
+            .chain([&rule.set])
+            .collect::<Vec<_>>();
+        let config = self.config.client_config(flow, &overlays);
+        let tag = format!("{flow}-{id}::{}", config.mode);
+        debug_tagged!(tag, "Stream: {stream:?}");
+        info_tagged!(tag, "Using rule: {name}");
+        let client = create_client(
+            flow,
+            self.inner.clone(),
+            || {
+                let (clsctx, params) = &self.activation;
+                unsafe {
+                    self.device
+                        .Activate::<IAudioClient3>(*clsctx, params.as_ref().map(|p| p as *const _))
+                }
+            },
+            RedirectClientInfo::new(config, tag.into()),
+        )?;
+        if let Some(properties) = self.properties.get() {
+            unsafe { client.SetClientProperties(&properties)? }
         }
+        Ok(client)
     }
 }
-impl IAudioClient_Impl for RedirectCompatAudioClient_Impl {
-    impl_boilerplate!(IAudioClient1);
+impl IAudioClient_Impl for RedirectDeferredAudioClient_Impl {
     fn Initialize(
         &self,
         sharemode: AUDCLNT_SHAREMODE,
@@ -915,28 +1220,232 @@ impl IAudioClient_Impl for RedirectCompatAudioClient_Impl {
         pformat: *const WAVEFORMATEX,
         audiosessionguid: *const GUID,
     ) -> WinResult<()> {
-        if sharemode == AUDCLNT_SHAREMODE_EXCLUSIVE {
-            warn_tagged!(@self, "Rejected exclusive init");
-            return AUDCLNT_E_EXCLUSIVE_MODE_NOT_ALLOWED.ok();
+        self.initialize(streamflags, pformat, audiosessionguid, |client| unsafe {
+            client.Initialize(
+                sharemode,
+                streamflags,
+                hnsbufferduration,
+                hnsperiodicity,
+                pformat,
+                Some(audiosessionguid),
+            )
+        })
+    }
+
+    fn GetBufferSize(&self) -> WinResult<u32> {
+        unsafe { self.client().GetBufferSize() }
+    }
+
+    fn GetStreamLatency(&self) -> WinResult<i64> {
+        unsafe { self.client().GetStreamLatency() }
+    }
+
+    fn GetCurrentPadding(&self) -> WinResult<u32> {
+        unsafe { self.client().GetCurrentPadding() }
+    }
+
+    fn IsFormatSupported(
+        &self,
+        sharemode: AUDCLNT_SHAREMODE,
+        pformat: *const WAVEFORMATEX,
+        ppclosestmatch: *mut *mut WAVEFORMATEX,
+    ) -> HRESULT {
+        unsafe {
+            self.client()
+                .IsFormatSupported(sharemode, pformat, Some(ppclosestmatch))
         }
-        info_tagged!(
-            @self,
-            "Initialize -> setting hooker, original dur = {hnsbufferduration} * 100ns"
-        );
-        if streamflags & AUDCLNT_STREAMFLAGS_LOOPBACK == 0 {
-            let calculated_dur = self
-                .info
-                .config
-                .compat_buf_len(self.info.param(&self.inner)?)
-                .unwrap_or_default();
-            info_tagged!(@self, "Inner dur = {calculated_dur} * 100ns");
-            unsafe {
-                self.inner.Initialize(
-                    sharemode,
-                    streamflags,
-                    calculated_dur,
-                    hnsperiodicity,
-                    pformat,
+    }
+
+    fn GetMixFormat(&self) -> WinResult<*mut WAVEFORMATEX> {
+        unsafe { self.client().GetMixFormat() }
+    }
+
+    fn GetDevicePeriod(
+        &self,
+        phnsdefaultdeviceperiod: *mut i64,
+        phnsminimumdeviceperiod: *mut i64,
+    ) -> WinResult<()> {
+        unsafe {
+            self.client()
+                .GetDevicePeriod(Some(phnsdefaultdeviceperiod), Some(phnsminimumdeviceperiod))
+        }
+    }
+
+    fn Start(&self) -> WinResult<()> {
+        unsafe { self.client().Start() }
+    }
+
+    fn Stop(&self) -> WinResult<()> {
+        unsafe { self.client().Stop() }
+    }
+
+    fn Reset(&self) -> WinResult<()> {
+        unsafe { self.client().Reset() }
+    }
+
+    fn SetEventHandle(&self, eventhandle: HANDLE) -> WinResult<()> {
+        unsafe { self.client().SetEventHandle(eventhandle) }
+    }
+
+    fn GetService(&self, riid: *const GUID, ppv: *mut *mut c_void) -> WinResult<()> {
+        let client = self.client();
+        unsafe {
+            (client.cast::<IAudioClient>()?.vtable().GetService)(client.as_raw(), riid, ppv).ok()
+        }
+    }
+}
+
+impl IAudioClient2_Impl for RedirectDeferredAudioClient_Impl {
+    fn IsOffloadCapable(&self, category: AUDIO_STREAM_CATEGORY) -> WinResult<BOOL> {
+        unsafe { self.client().IsOffloadCapable(category) }
+    }
+
+    fn SetClientProperties(&self, pproperties: *const AudioClientProperties) -> WinResult<()> {
+        if self.resolved.get().is_none() {
+            self.properties
+                .set(unsafe { pproperties.as_ref() }.copied());
+        }
+        unsafe { self.client().SetClientProperties(pproperties) }
+    }
+
+    fn GetBufferSizeLimits(
+        &self,
+        pformat: *const WAVEFORMATEX,
+        beventdriven: BOOL,
+        phnsminbufferduration: *mut i64,
+        phnsmaxbufferduration: *mut i64,
+    ) -> WinResult<()> {
+        unsafe {
+            self.client().GetBufferSizeLimits(
+                pformat,
+                beventdriven.into(),
+                phnsminbufferduration,
+                phnsmaxbufferduration,
+            )
+        }
+    }
+}
+
+impl IAudioClient3_Impl for RedirectDeferredAudioClient_Impl {
+    fn GetSharedModeEnginePeriod(
+        &self,
+        pformat: *const WAVEFORMATEX,
+        pdefaultperiodinframes: *mut u32,
+        pfundamentalperiodinframes: *mut u32,
+        pminperiodinframes: *mut u32,
+        pmaxperiodinframes: *mut u32,
+    ) -> WinResult<()> {
+        unsafe {
+            self.client().GetSharedModeEnginePeriod(
+                pformat,
+                pdefaultperiodinframes,
+                pfundamentalperiodinframes,
+                pminperiodinframes,
+                pmaxperiodinframes,
+            )
+        }
+    }
+
+    fn GetCurrentSharedModeEnginePeriod(
+        &self,
+        ppformat: *mut *mut WAVEFORMATEX,
+        pcurrentperiodinframes: *mut u32,
+    ) -> WinResult<()> {
+        unsafe {
+            self.client()
+                .GetCurrentSharedModeEnginePeriod(ppformat, pcurrentperiodinframes)
+        }
+    }
+
+    fn InitializeSharedAudioStream(
+        &self,
+        streamflags: u32,
+        periodinframes: u32,
+        pformat: *const WAVEFORMATEX,
+        audiosessionguid: *const GUID,
+    ) -> WinResult<()> {
+        self.initialize(streamflags, pformat, audiosessionguid, |client| unsafe {
+            client.InitializeSharedAudioStream(
+                streamflags,
+                periodinframes,
+                pformat,
+                Some(audiosessionguid),
+            )
+        })
+    }
+}
+
+impl Drop for RedirectDeferredAudioClient {
+    fn drop(&mut self) {
+        debug_tagged!(self.tag, "Client dropped")
+    }
+}
+
+#[implement(IAudioClient3)]
+struct RedirectCompatAudioClient {
+    inner: IAudioClient3,
+    hooker: IAudioClient3,
+    info: RedirectClientInfo,
+    outer: OnceCell<IAudioRenderClient>,
+    align: Cell<u16>,
+}
+
+impl RedirectCompatAudioClient {
+    fn new(inner: IAudioClient3, hooker: IAudioClient3, info: RedirectClientInfo) -> Self {
+        Self {
+            inner,
+            hooker,
+            info,
+            outer: OnceCell::new(),
+            align: 0.into(),
+        }
+    }
+    fn latency(&self) -> WinResult<StreamLatency> {
+        let param = self.info.param(&self.inner)?;
+        let prefill = self.outer.get().map(|client| {
+            let client: &RedirectCompatAudioRenderClient = unsafe { client.as_impl() };
+            ("prefill", client.buffer_len.1)
+        });
+        Ok(StreamLatency::new(
+            param,
+            unsafe { self.inner.GetBufferSize()? },
+            prefill,
+        ))
+    }
+}
+impl IAudioClient_Impl for RedirectCompatAudioClient_Impl {
+    impl_boilerplate!(IAudioClient1);
+    fn Initialize(
+        &self,
+        sharemode: AUDCLNT_SHAREMODE,
+        streamflags: u32,
+        hnsbufferduration: i64,
+        hnsperiodicity: i64,
+        pformat: *const WAVEFORMATEX,
+        audiosessionguid: *const GUID,
+    ) -> WinResult<()> {
+        if sharemode == AUDCLNT_SHAREMODE_EXCLUSIVE {
+            warn_tagged!(@self, "Rejected exclusive init");
+            return AUDCLNT_E_EXCLUSIVE_MODE_NOT_ALLOWED.ok();
+        }
+        info_tagged!(
+            @self,
+            "Initialize -> setting hooker, original dur = {hnsbufferduration} * 100ns"
+        );
+        if streamflags & AUDCLNT_STREAMFLAGS_LOOPBACK == 0 {
+            let calculated_dur = self
+                .info
+                .param(&self.inner)?
+                .compat_buf_len()
+                .unwrap_or_default();
+            info_tagged!(@self, "Inner dur = {calculated_dur} * 100ns");
+            unsafe {
+                self.inner.Initialize(
+                    sharemode,
+                    streamflags,
+                    calculated_dur,
+                    hnsperiodicity,
+                    pformat,
                     Some(audiosessionguid),
                 )?
             }
@@ -981,9 +1490,13 @@ impl IAudioClient_Impl for RedirectCompatAudioClient_Impl {
 
     fn Start(&self) -> WinResult<()> {
         info_tagged!(@self, "Start called");
+        self.info.log_latency(|| self.latency());
         if let Some(client) = self.outer.get() {
             let client: &RedirectCompatAudioRenderClient = unsafe { client.as_impl() };
             client.trick.set(false);
+            if let Some((fader, _)) = client.fade() {
+                fader.fade_in()
+            }
         }
         unsafe {
             _ = self.hooker.Start();
@@ -993,6 +1506,14 @@ impl IAudioClient_Impl for RedirectCompatAudioClient_Impl {
 
     fn Stop(&self) -> WinResult<()> {
         info_tagged!(@self, "Stop called");
+        if let Some(client) = self.outer.get() {
+            let client: &RedirectCompatAudioRenderClient = unsafe { client.as_impl() };
+            match client.fade_tail() {
+                Ok(0) => {}
+                Ok(frames) => wait_frames(frames, self.info.samplerate.get()),
+                Err(e) => warn_tagged!(@self, "Unable to queue the fade-out: {e}"),
+            }
+        }
         unsafe {
             _ = self.hooker.Stop();
             self.inner.Stop()
@@ -1004,7 +1525,11 @@ impl IAudioClient_Impl for RedirectCompatAudioClient_Impl {
         if let Some(client) = self.outer.get() {
             let client: &RedirectCompatAudioRenderClient = unsafe { client.as_impl() };
             client.trick.set(true);
+            if let Some((fader, _)) = client.fade() {
+                fader.restart()
+            }
         }
+        self.info.offset.store(0, Ordering::Relaxed);
         unsafe {
             _ = self.hooker.Reset();
             self.inner.Reset()
@@ -1030,7 +1555,7 @@ impl IAudioClient_Impl for RedirectCompatAudioClient_Impl {
                     let param = self.info.param(&self.inner)?;
                     let hooker_buffer_len = match (
                         unsafe { self.hooker.GetBufferSize().ok() },
-                        self.info.config.target_buf_len(param),
+                        param.target_buf_len(),
                     ) {
                         (None, None) => param.current_period + param.fundamental,
                         (None, Some(len)) => {
@@ -1043,20 +1568,36 @@ impl IAudioClient_Impl for RedirectCompatAudioClient_Impl {
                     };
                     let inner_buffer_len = unsafe { self.inner.GetBufferSize()? };
                     let align = AudioAlign::new(self.align.get());
+                    let fade = self
+                        .info
+                        .fader(align.frames_to_bytes(1))
+                        .zip(self.info.format.get())
+                        .map(|(fader, format)| {
+                            let tail =
+                                Concealer::new(format, align.frames_to_bytes(1), fader.len(), 1);
+                            (fader, tail).into()
+                        });
                     let service: IAudioRenderClient = RedirectCompatAudioRenderClient {
                         inner: unsafe { self.inner.GetService::<IAudioRenderClient>()? },
+                        client: self.inner.clone(),
+                        fade,
+                        data: std::ptr::null_mut::<u8>().into(),
                         trick_buffer: vec![0; align.frames_to_bytes(inner_buffer_len as usize)]
                             .into_boxed_slice()
                             .into(),
                         trick: true.into(),
                         align,
                         buffer_len: (inner_buffer_len, hooker_buffer_len),
+                        offset: self.info.offset.clone(),
                         tag: format!("{}-client", self.info.tag).into(),
                     }
                     .into();
                     unsafe { service.query(riid, ppv).ok() }
                 }
             }
+            IAudioClock::IID | IAudioClock2::IID if self.info.initialized() => {
+                self.info.clock(&self.inner, riid, ppv)
+            }
             _ => unsafe {
                 (self.inner.cast::<IAudioClient>()?.vtable().GetService)(
                     self.inner.as_raw(),
@@ -1107,7 +1648,7 @@ impl IAudioClient3_Impl for RedirectCompatAudioClient_Impl {
             unsafe { self.inner.SetClientProperties(&properties) }?;
         }
         self.align.set(unsafe { (*pformat).nBlockAlign });
-        self.info.initialized.set(true);
+        self.info.initialize(pformat);
         let client = if periodinframes != 0 {
             warn_tagged!(
                 @self,
@@ -1132,10 +1673,14 @@ drop_boilerplate!(RedirectCompatAudioClient);
 #[implement(IAudioRenderClient)]
 struct RedirectCompatAudioRenderClient {
     inner: IAudioRenderClient,
+    client: IAudioClient3,
+    fade: Option<UnsafeCell<(Fader, Concealer)>>,
+    data: Cell<*mut u8>,
     trick_buffer: UnsafeCell<Box<[u8]>>,
     trick: Cell<bool>,
     align: AudioAlign,
     buffer_len: (u32, u32),
+    offset: Arc<AtomicI64>,
     tag: Box<str>,
 }
 impl RedirectCompatAudioRenderClient {
@@ -1146,9 +1691,48 @@ impl RedirectCompatAudioRenderClient {
             let slice_to_write = from_raw_parts_mut(self.inner.GetBuffer(len)?, read_len);
             let slice = &(&*self.trick_buffer.get())[..read_len];
             slice_to_write.copy_from_slice(slice);
+            self.shape(slice_to_write, dwflags);
             self.inner.ReleaseBuffer(len, dwflags)
         }
     }
+    #[allow(clippy::mut_from_ref)]
+    fn fade(&self) -> Option<&mut (Fader, Concealer)> {
+        self.fade.as_ref().map(|fade| unsafe { &mut *fade.get() })
+    }
+    fn shape(&self, data: &mut [u8], dwflags: u32) {
+        if dwflags & AUDCLNT_BUFFERFLAGS_SILENT.0 as u32 == 0
+            && let Some((fader, tail)) = self.fade()
+        {
+            fader.apply(data);
+            tail.record(data);
+        }
+    }
+    /// Queues a fade-out of the last written frames, returns how long to wait for it.
+    fn fade_tail(&self) -> WinResult<u32> {
+        let Some((fader, tail)) = self.fade() else {
+            return Ok(0);
+        };
+        if self.trick.get() || fader.silent() {
+            return Ok(0);
+        }
+        let len = fader.len();
+        let pad = unsafe { self.client.GetCurrentPadding()? };
+        if pad + len <= self.buffer_len.0 {
+            unsafe {
+                tail.conceal(from_raw_parts_mut(
+                    self.inner.GetBuffer(len)?,
+                    self.align.frames_to_bytes(len as usize),
+                ));
+                self.inner.ReleaseBuffer(len, 0)?
+            }
+            self.offset.fetch_sub(len as i64, Ordering::Relaxed);
+            debug_tagged!(self.tag, "fading out over {len} frames after {pad} frames");
+            fader.mute();
+            return Ok(len);
+        }
+        fader.mute();
+        Ok(0)
+    }
 }
 impl IAudioRenderClient_Impl for RedirectCompatAudioRenderClient_Impl {
     fn GetBuffer(&self, numframesrequested: u32) -> WinResult<*mut u8> {
@@ -1159,7 +1743,14 @@ impl IAudioRenderClient_Impl for RedirectCompatAudioRenderClient_Impl {
             );
             Ok(unsafe { *self.trick_buffer.get().cast() })
         } else {
-            unsafe { self.inner.GetBuffer(numframesrequested) }
+            let data = unsafe { self.inner.GetBuffer(numframesrequested)? };
+            if let Some((fader, _)) = self.fade() {
+                if unsafe { self.client.GetCurrentPadding()? } == 0 {
+                    fader.restart()
+                }
+                self.data.set(data);
+            }
+            Ok(data)
         }
     }
 
@@ -1176,9 +1767,15 @@ impl IAudioRenderClient_Impl for RedirectCompatAudioRenderClient_Impl {
                         "filling silent buffer, {} frames filled",
                         self.buffer_len.1
                     );
+                    self.offset.fetch_add(
+                        numframeswritten as i64 - self.buffer_len.1 as i64,
+                        Ordering::Relaxed,
+                    );
                     self.apply_data(self.buffer_len.1, dwflags)
                 } else {
                     info_tagged!(self.tag, "already filled, discarding");
+                    self.offset
+                        .fetch_add(numframeswritten as i64, Ordering::Relaxed);
                     Ok(())
                 }
             } else {
@@ -1190,6 +1787,16 @@ impl IAudioRenderClient_Impl for RedirectCompatAudioRenderClient_Impl {
                     self.tag,
                     "no data written in this release call, overflow may happen!"
                 );
+            } else if self.fade.is_some() {
+                self.shape(
+                    unsafe {
+                        from_raw_parts_mut(
+                            self.data.get(),
+                            self.align.frames_to_bytes(numframeswritten as usize),
+                        )
+                    },
+                    dwflags,
+                );
             }
             unsafe { self.inner.ReleaseBuffer(numframeswritten, dwflags) }
         }
@@ -1200,31 +1807,144 @@ impl IAudioRenderClient_Impl for RedirectCompatAudioRenderClient_Impl {
 struct RedirectRingbufAudioClient {
     inner: IAudioClient3,
     info: RedirectClientInfo,
+    dataflow: DeviceDataFlow,
     buffer: Cell<u32>,
+    packet: Cell<u32>,
     align: Cell<AudioAlign>,
-    outer: OnceCell<(IAudioRenderClient, IRtwqAsyncCallback)>,
+    outer: OnceCell<(RingbufOuter, IRtwqAsyncCallback)>,
     app_handle: Cell<HANDLE>,
+    counters: Arc<RingCounters>,
+    headroom: Cell<u32>,
+    fade: Cell<u32>,
+}
+
+enum RingbufOuter {
+    Render(IAudioRenderClient),
+    Capture(IAudioCaptureClient),
 }
 
 impl RedirectRingbufAudioClient {
-    fn new(inner: IAudioClient3, info: RedirectClientInfo) -> Self {
+    fn new(dataflow: DeviceDataFlow, inner: IAudioClient3, info: RedirectClientInfo) -> Self {
         Self {
             inner,
             info,
+            dataflow,
             buffer: 0.into(),
+            packet: 0.into(),
             align: AudioAlign::new(0).into(),
             outer: OnceCell::new(),
             app_handle: Cell::default(),
+            counters: Arc::default(),
+            headroom: 0.into(),
+            fade: 0.into(),
+        }
+    }
+    fn set_packet(&self, param: &Shared3Info, period: u32) -> WinResult<()> {
+        if period != 0 {
+            self.packet.set(period)
+        } else if self.packet.get() == 0 {
+            let mut default_period = 0;
+            unsafe {
+                self.inner
+                    .GetDevicePeriod(Some(&mut default_period), None)?
+            };
+            self.packet
+                .set((default_period as u64 * param.samplerate as u64 / 10_000_000) as u32)
         }
+        self.buffer.update(|x| x.max(self.packet.get() * 2));
+        Ok(())
+    }
+    fn latency(&self) -> WinResult<StreamLatency> {
+        let param = self.info.param(&self.inner)?;
+        Ok(match self.dataflow {
+            DeviceDataFlow::Playback => StreamLatency::new(
+                param,
+                self.info.config.ring_engine_fill.queued(
+                    self.info.engine_len(&self.inner)?,
+                    self.info.config.engine_mark(param),
+                ),
+                Some(
+                    param
+                        .ring_target()
+                        .filter(|_| self.info.format.get().is_some())
+                        .map_or(("ring", self.buffer.get()), |target| {
+                            ("ring target", target.min(self.buffer.get()))
+                        }),
+                ),
+            ),
+            DeviceDataFlow::Capture => {
+                StreamLatency::new(param, 0, Some(("packet", self.packet.get())))
+            }
+        })
+    }
+    fn adaptive(&self, param: &Shared3Info) -> Option<(FillController, Splicer)> {
+        let target = param.ring_target()?.min(self.buffer.get());
+        let Some(format) = self.info.format.get() else {
+            warn_tagged!(@self, "Unsupported sample format, adaptive fill disabled");
+            return None;
+        };
+        info_tagged!(@self, "Adaptive fill target: {target} frames");
+        self.headroom.set(self.buffer.get() - target);
+        Some((
+            FillController::new(target, param.current_period),
+            Splicer::new(
+                format,
+                self.align.get().frames_to_bytes(1),
+                param.current_period / 2,
+            ),
+        ))
+    }
+    fn wait_fade(&self, thread: &RedirectRingbufThread, fade: u32) -> WinResult<()> {
+        unsafe { ResetEvent(*thread.faded)? };
+        thread.stopping.store(true, Ordering::Relaxed);
+        let period = self.info.param(&self.inner)?.current_period;
+        let samplerate = self.info.samplerate.get().max(1) as u64;
+        let timeout = ((fade + period * 2) as u64 * 1000).div_ceil(samplerate);
+        match unsafe { WaitForSingleObject(*thread.faded, timeout as u32) } {
+            WAIT_OBJECT_0 => Ok(()),
+            WAIT_TIMEOUT => {
+                warn_tagged!(@self, "Fade-out not written after {timeout}ms");
+                Ok(())
+            }
+            _ => Err(Error::from_thread()),
+        }
+    }
+    fn start_threshold(&self, param: &Shared3Info) -> u32 {
+        let len = self.buffer.get() - self.headroom.get();
+        let threshold = self.info.config.start_threshold(param.samplerate);
+        if threshold > len {
+            warn_tagged!(@self, "Start threshold {threshold} exceeds the usable ring, clamped to {len}");
+        }
+        let threshold = threshold.min(len);
+        if threshold != 0 {
+            info_tagged!(@self, "Start threshold: {threshold} frames");
+        }
+        threshold
+    }
+    fn concealer(&self, param: &Shared3Info) -> Option<Concealer> {
+        let periods = match self.info.config.ring_underrun {
+            RingUnderrun::FadeOut => 1,
+            RingUnderrun::Repeat => REPEAT_PERIODS,
+            _ => return None,
+        };
+        let Some(format) = self.info.format.get() else {
+            warn_tagged!(@self, "Unsupported sample format, concealing underruns with silence");
+            return None;
+        };
+        Some(Concealer::new(
+            format,
+            self.align.get().frames_to_bytes(1),
+            param.current_period,
+            periods,
+        ))
     }
     fn set_buffer(&self, param: &Shared3Info) {
         self.buffer.update(|x| {
             if x != 0 {
                 x
             } else {
-                self.info
-                    .config
-                    .ring_buf_len(param)
+                param
+                    .ring_buf_len()
                     .unwrap_or_else(|| param.current_period * 10)
             }
         })
@@ -1282,8 +2002,15 @@ impl IAudioClient_Impl for RedirectRingbufAudioClient_Impl {
             if let Some(phnsdefaultdeviceperiod) = unsafe { phnsdefaultdeviceperiod.as_mut() } {
                 let param = self.info.param(&self.inner)?;
                 self.set_buffer(param);
+                let reported = match self.dataflow {
+                    DeviceDataFlow::Playback => self.buffer.get(),
+                    DeviceDataFlow::Capture => {
+                        self.set_packet(param, 0)?;
+                        self.packet.get()
+                    }
+                };
                 *phnsdefaultdeviceperiod =
-                    calculate_period(param.samplerate, self.buffer.get()).max(minimumdeviceperiod)
+                    calculate_period(param.samplerate, reported).max(minimumdeviceperiod)
             }
             if let Some(phnsminimumdeviceperiod) = unsafe { phnsminimumdeviceperiod.as_mut() } {
                 *phnsminimumdeviceperiod = minimumdeviceperiod
@@ -1310,25 +2037,32 @@ impl IAudioClient_Impl for RedirectRingbufAudioClient_Impl {
 
     fn GetCurrentPadding(&self) -> WinResult<u32> {
         trace_tagged!(@self, "GetCurrentPadding called");
-        if let Some((outer, _)) = self.outer.get() {
-            let outer: &RedirectRingbufAudioRenderClient = unsafe { outer.as_impl() };
-            let buf = unsafe { &*outer.buffer.get() };
-            let len = self.buffer.get();
-            Ok(if buf.is_full() {
-                len
-            } else {
-                len - self.align.get().bytes_to_frames(buf.slots()) as u32
-            })
-        } else {
-            unsafe { self.inner.GetCurrentPadding() }
+        match self.outer.get() {
+            Some((RingbufOuter::Render(outer), _)) => {
+                let outer: &RedirectRingbufAudioRenderClient = unsafe { outer.as_impl() };
+                let buf = unsafe { &*outer.writer.get() }.producer();
+                let fill = self
+                    .align
+                    .get()
+                    .bytes_to_frames(buf.buffer().capacity() - buf.slots());
+                Ok((fill as u32 + self.headroom.get()).min(self.buffer.get()))
+            }
+            Some((RingbufOuter::Capture(outer), _)) => {
+                let outer: &RedirectRingbufAudioCaptureClient = unsafe { outer.as_impl() };
+                Ok(outer.available().min(self.buffer.get()))
+            }
+            None => unsafe { self.inner.GetCurrentPadding() },
         }
     }
 
     fn Start(&self) -> WinResult<()> {
         info_tagged!(@self, "Start called");
+        self.info.log_latency(|| self.latency());
         if let Some((outer, rthread)) = self.outer.get() {
-            let outer: &RedirectRingbufAudioRenderClient = unsafe { outer.as_impl() };
-            outer.trick.set(false);
+            if let RingbufOuter::Render(outer) = outer {
+                let outer: &RedirectRingbufAudioRenderClient = unsafe { outer.as_impl() };
+                outer.trick.set(false);
+            }
             let thread: &RedirectRingbufThread = unsafe { rthread.as_impl() };
             if !thread.init.is_completed() {
                 let result = unsafe { RtwqCreateAsyncResult(None, rthread, None)? };
@@ -1339,6 +2073,7 @@ impl IAudioClient_Impl for RedirectRingbufAudioClient_Impl {
                 });
                 unsafe { RtwqPutWorkItem(thread.thread_id, 1, &result)? }
             }
+            thread.stopping.store(false, Ordering::Relaxed);
             thread.pause.store(false, Ordering::Relaxed);
         }
         unsafe { self.inner.Start() }
@@ -1348,19 +2083,39 @@ impl IAudioClient_Impl for RedirectRingbufAudioClient_Impl {
         info_tagged!(@self, "Stop called");
         if let Some((_, thread)) = self.outer.get() {
             let thread: &RedirectRingbufThread = unsafe { thread.as_impl() };
+            let fade = self.fade.get();
+            if fade != 0
+                && thread.init.is_completed()
+                && !thread.pause.load(Ordering::Relaxed)
+                && let Err(e) = self.wait_fade(thread, fade)
+            {
+                warn_tagged!(@self, "Unable to wait for the fade-out: {e}")
+            }
             thread.pause.store(true, Ordering::Relaxed);
         }
+        self.counters.log(&self.info.tag);
         unsafe { self.inner.Stop() }
     }
 
     fn Reset(&self) -> WinResult<()> {
         info_tagged!(@self, "Reset called");
-        if let Some((outer, thread)) = self.outer.get() {
-            let thread: &RedirectRingbufThread = unsafe { thread.as_impl() };
-            while unsafe { &mut *thread.buffer.get() }.pop().is_ok() {}
-            let outer: &RedirectRingbufAudioRenderClient = unsafe { outer.as_impl() };
-            outer.trick.set(true);
+        match self.outer.get() {
+            Some((RingbufOuter::Render(outer), thread)) => {
+                let thread: &RedirectRingbufThread = unsafe { thread.as_impl() };
+                if let RingbufIo::Render { buffer, .. } = &thread.io {
+                    while unsafe { &mut *buffer.get() }.pop().is_ok() {}
+                }
+                thread.reset.store(true, Ordering::Release);
+                let outer: &RedirectRingbufAudioRenderClient = unsafe { outer.as_impl() };
+                outer.trick.set(true);
+            }
+            Some((RingbufOuter::Capture(outer), _)) => {
+                let outer: &RedirectRingbufAudioCaptureClient = unsafe { outer.as_impl() };
+                outer.clear();
+            }
+            None => {}
         }
+        self.info.offset.store(0, Ordering::Relaxed);
         unsafe { self.inner.Reset() }
     }
 
@@ -1377,53 +2132,141 @@ impl IAudioClient_Impl for RedirectRingbufAudioClient_Impl {
     fn GetService(&self, riid: *const GUID, ppv: *mut *mut c_void) -> WinResult<()> {
         let iid = unsafe { *riid };
         debug_tagged!(@self, "GetService called, iid: {iid:?}");
+        let service = match self.dataflow {
+            DeviceDataFlow::Playback => IAudioRenderClient::IID,
+            DeviceDataFlow::Capture => IAudioCaptureClient::IID,
+        };
         match iid {
-            IAudioRenderClient::IID if self.info.initialized() => {
+            _ if iid == service && self.info.initialized() => {
                 if let Some((client, _)) = self.outer.get() {
-                    unsafe { client.query(riid, ppv).ok() }
+                    match client {
+                        RingbufOuter::Render(client) => unsafe { client.query(riid, ppv).ok() },
+                        RingbufOuter::Capture(client) => unsafe { client.query(riid, ppv).ok() },
+                    }
                 } else {
                     let param = self.info.param(&self.inner)?;
                     let align = self.align.get();
+                    let overflow = self.info.config.ring_overflow;
                     let buffer = align.frames_to_bytes(self.buffer.get() as usize);
+                    let capacity = match overflow {
+                        RingOverflow::DropOldest => buffer * 2,
+                        _ => buffer,
+                    };
                     let event_handle = unsafe { CreateEventW(None, false, false, None)? };
+                    let faded = unsafe { Owned::new(CreateEventW(None, true, false, None)?) };
                     unsafe { self.inner.SetEventHandle(event_handle)? }
-                    let (producer, consumer) = RingBuffer::new(buffer);
+                    let (producer, consumer) = RingBuffer::new(capacity);
                     unsafe { RtwqStartup()? };
                     let mut ids = [0; 2];
                     unsafe { RtwqLockSharedWorkQueue(w!("Audio"), 1, &mut ids[0], &mut ids[1])? };
-                    let buf_size = unsafe { self.inner.GetBufferSize()? };
-                    let real_size = self
-                        .info
-                        .config
-                        .target_buf_len(param)
-                        .map_or(buf_size, |len| len.clamp(param.current_period, buf_size));
+                    let (io, client) = match self.dataflow {
+                        DeviceDataFlow::Playback => {
+                            let released = Arc::new(AtomicU64::new(0));
+                            let fader = self.info.fader(align.frames_to_bytes(1));
+                            self.fade.set(fader.as_ref().map_or(0, Fader::len));
+                            let adapt = self.adaptive(param);
+                            let threshold = self.start_threshold(param);
+                            let engine = self.info.config.ring_engine_fill;
+                            let mark = self.info.config.engine_mark(param);
+                            if engine != EngineFill::Fill {
+                                info_tagged!(@self, "Engine fill: {engine}, mark {mark} frames");
+                            }
+                            let io = RingbufIo::Render {
+                                buffer: consumer.into(),
+                                inner: unsafe { self.inner.GetService::<IAudioRenderClient>()? },
+                                real_len: self.info.engine_len(&self.inner)?,
+                                offset: self.info.offset.clone(),
+                                state: RenderState {
+                                    adapt,
+                                    underrun: self.info.config.ring_underrun,
+                                    conceal: self.concealer(param),
+                                    period: param.current_period,
+                                    released: released.clone(),
+                                    seen: 0,
+                                    idle: 0,
+                                    stall: self.info.config.ring_stall_periods,
+                                    fader,
+                                    threshold,
+                                    preroll: (threshold != 0).then_some(0),
+                                    engine,
+                                    mark,
+                                }
+                                .into(),
+                            };
+                            let client = RedirectRingbufAudioRenderClient {
+                                writer: RingWriter::new(producer, buffer).into(),
+                                len: buffer,
+                                overflow,
+                                align,
+                                trick: true.into(),
+                                offset: self.info.offset.clone(),
+                                counters: self.counters.clone(),
+                                released,
+                                tag: format!("{}-render", self.info.tag).into(),
+                            };
+                            (io, RingbufOuter::Render(client.into()))
+                        }
+                        DeviceDataFlow::Capture => {
+                            let packet = self.packet.get();
+                            let (packets_in, packets_out) = RingBuffer::new(
+                                align.bytes_to_frames(capacity) / param.fundamental as usize + 1,
+                            );
+                            info_tagged!(@self, "Packet length: {packet}");
+                            let io = RingbufIo::Capture {
+                                buffer: producer.into(),
+                                packets: packets_in.into(),
+                                inner: unsafe { self.inner.GetService::<IAudioCaptureClient>()? },
+                                packet_len: align.frames_to_bytes(packet as usize),
+                                discontinuity: false.into(),
+                            };
+                            let client = RedirectRingbufAudioCaptureClient {
+                                buffer: consumer.into(),
+                                packets: packets_out.into(),
+                                cache: vec![0u8; align.frames_to_bytes(packet as usize)]
+                                    .into_boxed_slice()
+                                    .into(),
+                                offset: 0.into(),
+                                discontinuity: false.into(),
+                                packet,
+                                len: self.buffer.get(),
+                                overflow,
+                                samplerate: param.samplerate,
+                                align,
+                                counters: self.counters.clone(),
+                                tag: format!("{}-capture", self.info.tag).into(),
+                            };
+                            (io, RingbufOuter::Capture(client.into()))
+                        }
+                    };
                     info_tagged!(@self,"Creating thread");
                     let callback = RedirectRingbufThread {
-                        buffer: consumer.into(),
+                        io,
                         client: self.inner.clone(),
-                        inner: unsafe { self.inner.GetService::<IAudioRenderClient>()? },
                         align,
-                        real_len: real_size,
+                        len: buffer,
+                        overflow,
+                        counters: self.counters.clone(),
                         event: unsafe { Owned::new(event_handle) },
+                        faded,
                         app_handle: None.into(),
                         thread_id: ids[1],
                         tag: format!("{}-thread", self.info.tag).into(),
                         pause: false.into(),
+                        stopping: false.into(),
+                        reset: false.into(),
                         init: Once::new(),
                     };
-                    let client: IAudioRenderClient = RedirectRingbufAudioRenderClient {
-                        buffer: producer.into(),
-                        cache: vec![0u8; buffer].into_boxed_slice().into(),
-                        align,
-                        trick: true.into(),
-                        tag: format!("{}-render", self.info.tag).into(),
-                    }
-                    .into();
-                    let ret = unsafe { client.query(riid, ppv) }.ok();
+                    let ret = match &client {
+                        RingbufOuter::Render(client) => unsafe { client.query(riid, ppv).ok() },
+                        RingbufOuter::Capture(client) => unsafe { client.query(riid, ppv).ok() },
+                    };
                     _ = self.outer.set((client, callback.into()));
                     ret
                 }
             }
+            IAudioClock::IID | IAudioClock2::IID if self.info.initialized() => {
+                self.info.clock(&self.inner, riid, ppv)
+            }
             _ => unsafe {
                 (self.inner.cast::<IAudioClient>()?.vtable().GetService)(
                     self.inner.as_raw(),
@@ -1470,9 +2313,9 @@ impl IAudioClient3_Impl for RedirectRingbufAudioClient_Impl {
                 "InitializeSharedAudioStream -> adding ring buffer, current period: {periodinframes}"
             );
         }
-        self.info.initialized.set(true);
+        self.info.initialize(pformat);
         unsafe {
-            let target_config = self.info.config;
+            let target_config = &self.info.config;
             if target_config.raw && !self.info.raw_flag.is_completed() {
                 info_tagged!(@self, "Applying raw flag");
                 let properties = AudioClientProperties {
@@ -1484,6 +2327,9 @@ impl IAudioClient3_Impl for RedirectRingbufAudioClient_Impl {
             }
             let param = self.info.param(&self.inner)?;
             self.set_buffer(param);
+            if self.dataflow == DeviceDataFlow::Capture {
+                self.set_packet(param, periodinframes)?;
+            }
             self.align.set(AudioAlign::new((*pformat).nBlockAlign));
             if streamflags & AUDCLNT_STREAMFLAGS_EVENTCALLBACK == 0 {
                 info_tagged!(@self, "Injecting event flag");
@@ -1504,61 +2350,393 @@ drop_boilerplate!(RedirectRingbufAudioClient);
 
 #[implement(IRtwqAsyncCallback)]
 struct RedirectRingbufThread {
-    buffer: UnsafeCell<Consumer<u8>>,
+    io: RingbufIo,
     client: IAudioClient3,
-    inner: IAudioRenderClient,
     align: AudioAlign,
-    real_len: u32,
+    len: usize,
+    overflow: RingOverflow,
+    counters: Arc<RingCounters>,
     event: Owned<HANDLE>,
+    faded: Owned<HANDLE>,
     app_handle: Cell<Option<HANDLE>>,
     thread_id: u32,
     tag: Box<str>,
     pause: AtomicBool,
+    stopping: AtomicBool,
+    reset: AtomicBool,
     init: Once,
 }
-impl IRtwqAsyncCallback_Impl for RedirectRingbufThread_Impl {
-    fn GetParameters(&self, _: *mut u32, pdwqueue: *mut u32) -> WinResult<()> {
-        unsafe { *pdwqueue = self.thread_id }
-        Ok(())
+
+enum RingbufIo {
+    Render {
+        buffer: UnsafeCell<Consumer<u8>>,
+        inner: IAudioRenderClient,
+        real_len: u32,
+        offset: Arc<AtomicI64>,
+        state: UnsafeCell<RenderState>,
+    },
+    Capture {
+        buffer: UnsafeCell<Producer<u8>>,
+        packets: UnsafeCell<Producer<CapturePacket>>,
+        inner: IAudioCaptureClient,
+        packet_len: usize,
+        discontinuity: Cell<bool>,
+    },
+}
+
+const REPEAT_PERIODS: u32 = 3;
+
+struct RenderState {
+    adapt: Option<(FillController, Splicer)>,
+    underrun: RingUnderrun,
+    conceal: Option<Concealer>,
+    period: u32,
+    released: Arc<AtomicU64>,
+    seen: u64,
+    idle: u32,
+    stall: u32,
+    fader: Option<Fader>,
+    threshold: u32,
+    preroll: Option<u32>,
+    engine: EngineFill,
+    mark: u32,
+}
+impl RenderState {
+    fn stalled(&self) -> bool {
+        self.stall != 0 && self.idle >= self.stall
+    }
+}
+
+#[derive(Default)]
+struct RingCounters {
+    overflows: AtomicU64,
+    dropped: AtomicU64,
+    prerolls: AtomicU64,
+    preroll_frames: AtomicU64,
+}
+impl RingCounters {
+    fn record(&self, frames: usize) {
+        self.overflows.fetch_add(1, Ordering::Relaxed);
+        self.dropped.fetch_add(frames as u64, Ordering::Relaxed);
+    }
+    fn preroll(&self, frames: u32) {
+        self.prerolls.fetch_add(1, Ordering::Relaxed);
+        self.preroll_frames
+            .fetch_add(frames as u64, Ordering::Relaxed);
+    }
+    fn log(&self, tag: &str) {
+        let overflows = self.overflows.load(Ordering::Relaxed);
+        if overflows != 0 {
+            let dropped = self.dropped.load(Ordering::Relaxed);
+            info_tagged!(
+                tag,
+                "Ring overflows: {overflows}, frames dropped: {dropped}"
+            );
+        }
+        let prerolls = self.prerolls.load(Ordering::Relaxed);
+        if prerolls != 0 {
+            let frames = self.preroll_frames.load(Ordering::Relaxed);
+            info_tagged!(tag, "Pre-rolls: {prerolls}, frames waited: {frames}");
+        }
     }
-    fn Invoke(&self, pasyncresult: Ref<IRtwqAsyncResult>) -> WinResult<()> {
-        let buffer = unsafe { &mut *self.buffer.get() };
-        if buffer.is_abandoned() {
-            return Ok(());
-        } else {
-            unsafe { RtwqPutWaitingWorkItem(*self.event, 1, pasyncresult.as_ref(), None)? }
-            if self.pause.load(Ordering::Relaxed) {
+}
+
+#[derive(Clone, Copy)]
+struct CapturePacket {
+    position: u64,
+    qpc: u64,
+    frames: u32,
+    flags: u32,
+}
+
+impl RedirectRingbufThread {
+    fn render(
+        &self,
+        buffer: &mut Consumer<u8>,
+        inner: &IAudioRenderClient,
+        real_len: u32,
+        offset: &AtomicI64,
+        state: &mut RenderState,
+    ) -> WinResult<()> {
+        if self.reset.swap(false, Ordering::Acquire) {
+            state.preroll = (state.threshold != 0).then_some(0);
+        }
+        let stopping = self.stopping.load(Ordering::Relaxed);
+        if let Some(fader) = &mut state.fader {
+            if stopping {
+                fader.fade_out()
+            } else {
+                fader.fade_in()
+            }
+            if fader.silent() {
+                if stopping {
+                    unsafe { SetEvent(*self.faded)? }
+                }
                 return Ok(());
             }
         }
+        if self.overflow == RingOverflow::DropOldest && buffer.slots() > self.len {
+            let excess = buffer.slots() - self.len;
+            if let Ok(chunk) = buffer.read_chunk(excess) {
+                chunk.commit_all()
+            }
+            let frames = self.align.bytes_to_frames(excess);
+            offset.fetch_add(frames as i64, Ordering::Relaxed);
+            self.counters.record(frames);
+            debug_tagged!(
+                self.tag,
+                "ring buffer overflow, dropped {frames} oldest frames"
+            );
+        }
+        let released = state.released.load(Ordering::Relaxed);
+        if released != state.seen {
+            if state.stalled() {
+                info_tagged!(self.tag, "producer resumed after {} periods", state.idle)
+            }
+            state.seen = released;
+            state.idle = 0;
+        } else {
+            state.idle = state.idle.saturating_add(1);
+            if state.stall != 0 && state.idle == state.stall {
+                warn_tagged!(
+                    self.tag,
+                    "producer stalled, no ReleaseBuffer for {} periods",
+                    state.stall
+                )
+            }
+        }
+        let fill = self.align.bytes_to_frames(buffer.slots());
+        if let Some(waited) = state.preroll {
+            if (fill as u32) < state.threshold {
+                state.preroll = Some(waited + state.period);
+                let pad = unsafe { self.client.GetCurrentPadding()? };
+                if pad < state.period {
+                    let frames = state.period - pad;
+                    unsafe {
+                        inner.GetBuffer(frames)?;
+                        inner.ReleaseBuffer(frames, AUDCLNT_BUFFERFLAGS_SILENT.0 as u32)?
+                    }
+                    offset.fetch_sub(frames as i64, Ordering::Relaxed);
+                }
+                return unsafe { self.app_handle.get().map_or(Ok(()), |h| SetEvent(h)) };
+            }
+            state.preroll = None;
+            self.counters.preroll(waited);
+            info_tagged!(self.tag, "pre-roll done after {waited} frames, fill {fill}");
+        }
+        let adjust = state
+            .adapt
+            .as_mut()
+            .map_or(Adjust::Keep, |(controller, _)| {
+                controller.update(fill as u32)
+            });
         if buffer.is_empty() {
             let pad = unsafe { self.client.GetCurrentPadding()? };
-            if pad == 0 {
-                warn_tagged!(self.tag, "buffer is empty, underflow may happen!")
-            } else {
-                debug_tagged!(self.tag, "mid-buffer empty, data in client buffer: {pad}")
+            let stalled = state.stalled();
+            if !stalled {
+                if pad == 0 {
+                    warn_tagged!(self.tag, "buffer is empty, underflow may happen!")
+                } else {
+                    debug_tagged!(self.tag, "mid-buffer empty, data in client buffer: {pad}")
+                }
+            }
+            if let Some(fader) = &mut state.fader {
+                if stopping {
+                    fader.mute()
+                } else if pad < state.period {
+                    fader.restart()
+                }
+            }
+            if (stalled || state.underrun != RingUnderrun::None) && pad < state.period {
+                let frames = state.period - pad;
+                let data = unsafe { inner.GetBuffer(frames)? };
+                match &mut state.conceal {
+                    Some(conceal) if !stalled => {
+                        conceal.conceal(unsafe {
+                            from_raw_parts_mut(data, self.align.frames_to_bytes(frames as usize))
+                        });
+                        unsafe { inner.ReleaseBuffer(frames, 0)? }
+                    }
+                    _ => unsafe {
+                        inner.ReleaseBuffer(frames, AUDCLNT_BUFFERFLAGS_SILENT.0 as u32)?
+                    },
+                }
+                offset.fetch_sub(frames as i64, Ordering::Relaxed);
+                if !stalled {
+                    debug_tagged!(self.tag, "concealed {frames} frames ({})", state.underrun)
+                }
             }
         } else {
-            let read_len = self.align.bytes_to_frames(buffer.slots());
-            let write_len = read_len
-                .min((self.real_len - unsafe { self.client.GetCurrentPadding()? }) as usize);
+            let pad = unsafe { self.client.GetCurrentPadding()? };
+            let room = state.engine.room(pad, real_len, state.mark) as usize;
+            if room == 0 {
+                return unsafe { self.app_handle.get().map_or(Ok(()), |h| SetEvent(h)) };
+            }
+            let room = state
+                .fader
+                .as_ref()
+                .map_or(room, |fader| room.min(fader.remaining() as usize));
+            let (write_len, adjust) = match &state.adapt {
+                Some((_, splicer)) => splicer.plan(fill, room, adjust),
+                None => (fill.min(room), Adjust::Keep),
+            };
             let slice = unsafe {
                 from_raw_parts_mut(
-                    self.inner.GetBuffer(write_len as u32)?,
+                    inner.GetBuffer(write_len as u32)?,
                     self.align.frames_to_bytes(write_len),
                 )
             };
-            buffer
-                .pop_entire_slice(slice)
-                .unwrap_or_else(|e| warn_tagged!(self.tag, "pop overflow! {e}"));
-            unsafe { self.inner.ReleaseBuffer(write_len as u32, 0)? };
-            trace_tagged!(
-                self.tag,
-                "data in mid-buffer: {read_len}, written: {write_len}"
-            )
+            match &mut state.adapt {
+                Some((controller, splicer)) if adjust != Adjust::Keep => {
+                    splicer
+                        .pop(buffer, slice, adjust)
+                        .unwrap_or_else(|e| warn_tagged!(self.tag, "pop overflow! {e}"));
+                    let shift = match adjust {
+                        Adjust::Drop(frames) => frames as i64,
+                        Adjust::Insert(frames) => -(frames as i64),
+                        Adjust::Keep => 0,
+                    };
+                    offset.fetch_add(shift, Ordering::Relaxed);
+                    debug_tagged!(
+                        self.tag,
+                        "fill {fill} around target {}, {adjust:?}",
+                        controller.target()
+                    )
+                }
+                _ => buffer
+                    .pop_entire_slice(slice)
+                    .unwrap_or_else(|e| warn_tagged!(self.tag, "pop overflow! {e}")),
+            }
+            if let Some(fader) = &mut state.fader {
+                fader.apply(slice)
+            }
+            if let Some(conceal) = &mut state.conceal {
+                conceal.record(slice)
+            }
+            unsafe { inner.ReleaseBuffer(write_len as u32, 0)? };
+            trace_tagged!(self.tag, "data in mid-buffer: {fill}, written: {write_len}")
         }
         unsafe { self.app_handle.get().map_or(Ok(()), |h| SetEvent(h)) }
     }
+    fn capture(
+        &self,
+        buffer: &mut Producer<u8>,
+        packets: &mut Producer<CapturePacket>,
+        inner: &IAudioCaptureClient,
+        packet_len: usize,
+        discontinuity: &Cell<bool>,
+    ) -> WinResult<()> {
+        loop {
+            let mut data = std::ptr::null_mut();
+            let (mut frames, mut flags, mut position, mut qpc) = (0, 0, 0, 0);
+            unsafe {
+                inner.GetBuffer(
+                    &mut data,
+                    &mut frames,
+                    &mut flags,
+                    Some(&mut position),
+                    Some(&mut qpc),
+                )?
+            };
+            if frames == 0 {
+                break;
+            }
+            let len = self.align.frames_to_bytes(frames as usize);
+            let fill = buffer.buffer().capacity() - buffer.slots();
+            let write = if packets.is_full() {
+                0
+            } else {
+                self.overflow
+                    .fit(len, self.len.saturating_sub(fill), buffer.slots())
+            };
+            if write != 0 {
+                if discontinuity.take() {
+                    flags |= AUDCLNT_BUFFERFLAGS_DATA_DISCONTINUITY.0 as u32;
+                }
+                _ = packets.push(CapturePacket {
+                    position,
+                    qpc,
+                    frames: self.align.bytes_to_frames(write) as u32,
+                    flags,
+                });
+                if flags & AUDCLNT_BUFFERFLAGS_SILENT.0 as u32 != 0 || data.is_null() {
+                    if let Ok(chunk) = buffer.write_chunk(write) {
+                        chunk.commit_all()
+                    }
+                } else {
+                    buffer
+                        .push_entire_slice(unsafe { std::slice::from_raw_parts(data, write) })
+                        .unwrap_or_else(|e| warn_tagged!(self.tag, "push overflow! {e}"));
+                }
+                trace_tagged!(self.tag, "captured: {frames}, position: {position}");
+            }
+            if write < len {
+                let dropped = self.align.bytes_to_frames(len - write);
+                warn_tagged!(
+                    self.tag,
+                    "ring buffer is full ({}), dropping {dropped} frames",
+                    self.overflow
+                );
+                self.counters.record(dropped);
+                discontinuity.set(true);
+            }
+            unsafe { inner.ReleaseBuffer(frames)? };
+        }
+        if buffer.buffer().capacity() - buffer.slots() >= packet_len {
+            unsafe { self.app_handle.get().map_or(Ok(()), |h| SetEvent(h)) }
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl IRtwqAsyncCallback_Impl for RedirectRingbufThread_Impl {
+    fn GetParameters(&self, _: *mut u32, pdwqueue: *mut u32) -> WinResult<()> {
+        unsafe { *pdwqueue = self.thread_id }
+        Ok(())
+    }
+    fn Invoke(&self, pasyncresult: Ref<IRtwqAsyncResult>) -> WinResult<()> {
+        let abandoned = match &self.io {
+            RingbufIo::Render { buffer, .. } => unsafe { &*buffer.get() }.is_abandoned(),
+            RingbufIo::Capture { buffer, .. } => unsafe { &*buffer.get() }.is_abandoned(),
+        };
+        if abandoned {
+            return Ok(());
+        } else {
+            unsafe { RtwqPutWaitingWorkItem(*self.event, 1, pasyncresult.as_ref(), None)? }
+            if self.pause.load(Ordering::Relaxed) {
+                return Ok(());
+            }
+        }
+        match &self.io {
+            RingbufIo::Render {
+                buffer,
+                inner,
+                real_len,
+                offset,
+                state,
+            } => self.render(
+                unsafe { &mut *buffer.get() },
+                inner,
+                *real_len,
+                offset,
+                unsafe { &mut *state.get() },
+            ),
+            RingbufIo::Capture {
+                buffer,
+                packets,
+                inner,
+                packet_len,
+                discontinuity,
+            } => self.capture(
+                unsafe { &mut *buffer.get() },
+                unsafe { &mut *packets.get() },
+                inner,
+                *packet_len,
+                discontinuity,
+            ),
+        }
+    }
 }
 impl Drop for RedirectRingbufThread {
     fn drop(&mut self) {
@@ -1569,16 +2747,21 @@ impl Drop for RedirectRingbufThread {
                     error_tagged!(self.tag, "Encountered error when closing thread: {e}")
                 });
         }
+        self.counters.log(&self.tag);
         info_tagged!(self.tag, "Consumer thread stopped");
     }
 }
 
 #[implement(IAudioRenderClient)]
 struct RedirectRingbufAudioRenderClient {
-    buffer: UnsafeCell<Producer<u8>>,
-    cache: UnsafeCell<Box<[u8]>>,
+    writer: UnsafeCell<RingWriter>,
+    len: usize,
+    overflow: RingOverflow,
     align: AudioAlign,
     trick: Cell<bool>,
+    offset: Arc<AtomicI64>,
+    counters: Arc<RingCounters>,
+    released: Arc<AtomicU64>,
     tag: Box<str>,
 }
 impl IAudioRenderClient_Impl for RedirectRingbufAudioRenderClient_Impl {
@@ -1594,9 +2777,11 @@ impl IAudioRenderClient_Impl for RedirectRingbufAudioRenderClient_Impl {
                 "GetBuffer called, requested: {numframesrequested}"
             );
         }
-        Ok(unsafe { &mut *self.cache.get() }.as_mut_ptr())
+        let len = self.align.frames_to_bytes(numframesrequested as usize);
+        Ok(unsafe { &mut *self.writer.get() }.get(len))
     }
     fn ReleaseBuffer(&self, numframeswritten: u32, dwflags: u32) -> WinResult<()> {
+        self.released.fetch_add(1, Ordering::Relaxed);
         if numframeswritten == 0 {
             return Ok(());
         }
@@ -1607,19 +2792,41 @@ impl IAudioRenderClient_Impl for RedirectRingbufAudioRenderClient_Impl {
             );
             if dwflags == 2 {
                 info_tagged!(self.tag, "discarding silent data");
+                self.offset
+                    .fetch_add(numframeswritten as i64, Ordering::Relaxed);
                 return Ok(());
             }
         }
         unsafe {
-            let buffer = &mut *self.buffer.get();
-            let slice = &mut (&mut *self.cache.get())
-                [..(self.align.frames_to_bytes(numframeswritten as usize))];
-            if dwflags == 2 {
-                slice.fill(0);
+            let writer = &mut *self.writer.get();
+            let buffer = writer.producer();
+            let len = self.align.frames_to_bytes(numframeswritten as usize);
+            let fill = buffer.buffer().capacity() - buffer.slots();
+            let write = self
+                .overflow
+                .fit(len, self.len.saturating_sub(fill), buffer.slots());
+            let pushed = if write == 0 {
+                Ok(())
+            } else {
+                writer.release(write, dwflags == 2)
+            };
+            let written = pushed.map_or_else(
+                |e| {
+                    warn_tagged!(self.tag, "push overflow! {e}");
+                    0
+                },
+                |_| write,
+            );
+            if written < len {
+                let dropped = self.align.bytes_to_frames(len - written);
+                warn_tagged!(
+                    self.tag,
+                    "ring buffer is full ({}), dropping {dropped} frames",
+                    self.overflow
+                );
+                self.offset.fetch_add(dropped as i64, Ordering::Relaxed);
+                self.counters.record(dropped);
             }
-            buffer
-                .push_entire_slice(slice)
-                .unwrap_or_else(|e| warn_tagged!(self.tag, "push overflow! {e}"));
             debug_tagged!(
                 self.tag,
                 "ReleaseBuffer called, written: {numframeswritten}"
@@ -1634,6 +2841,162 @@ impl Drop for RedirectRingbufAudioRenderClient {
     }
 }
 
+#[implement(IAudioCaptureClient)]
+struct RedirectRingbufAudioCaptureClient {
+    buffer: UnsafeCell<Consumer<u8>>,
+    packets: UnsafeCell<Consumer<CapturePacket>>,
+    cache: UnsafeCell<Box<[u8]>>,
+    offset: Cell<u32>,
+    discontinuity: Cell<bool>,
+    packet: u32,
+    len: u32,
+    overflow: RingOverflow,
+    samplerate: u32,
+    align: AudioAlign,
+    counters: Arc<RingCounters>,
+    tag: Box<str>,
+}
+impl RedirectRingbufAudioCaptureClient {
+    fn available(&self) -> u32 {
+        self.align
+            .bytes_to_frames(unsafe { &*self.buffer.get() }.slots()) as u32
+    }
+    fn clear(&self) {
+        let buffer = unsafe { &mut *self.buffer.get() };
+        if let Ok(chunk) = buffer.read_chunk(buffer.slots()) {
+            chunk.commit_all()
+        }
+        while unsafe { &mut *self.packets.get() }.pop().is_ok() {}
+        self.offset.set(0);
+        self.discontinuity.set(false);
+    }
+    fn advance(&self, frames: u32) -> WinResult<()> {
+        let buffer = unsafe { &mut *self.buffer.get() };
+        let len = self.align.frames_to_bytes(frames as usize);
+        buffer
+            .read_chunk(len)
+            .map_err(|_| Error::from_hresult(AUDCLNT_E_INVALID_SIZE))?
+            .commit_all();
+        let packets = unsafe { &mut *self.packets.get() };
+        let mut offset = self.offset.get() + frames;
+        while let Ok(&CapturePacket { frames, .. }) = packets.peek() {
+            if offset < frames {
+                break;
+            }
+            offset -= frames;
+            _ = packets.pop();
+        }
+        self.offset.set(offset);
+        Ok(())
+    }
+    fn trim(&self) -> WinResult<()> {
+        let available = self.available();
+        if self.overflow == RingOverflow::DropOldest && available > self.len {
+            let excess = available - self.len;
+            self.advance(excess)?;
+            self.discontinuity.set(true);
+            self.counters.record(excess as usize);
+            debug_tagged!(
+                self.tag,
+                "ring buffer overflow, dropped {excess} oldest frames"
+            );
+        }
+        Ok(())
+    }
+    fn position(&self, frames: u32) -> (u64, u64, u32) {
+        let packets = unsafe { &mut *self.packets.get() };
+        let Ok(chunk) = packets.read_chunk(packets.slots()) else {
+            return (0, 0, AUDCLNT_BUFFERFLAGS_TIMESTAMP_ERROR.0 as u32);
+        };
+        let (first, second) = chunk.as_slices();
+        let mut packets = first.iter().chain(second);
+        let Some(front) = packets.next() else {
+            return (0, 0, AUDCLNT_BUFFERFLAGS_TIMESTAMP_ERROR.0 as u32);
+        };
+        let offset = self.offset.get();
+        let mut flags = if offset == 0 { front.flags } else { 0 };
+        let mut covered = front.frames.saturating_sub(offset);
+        for packet in packets {
+            if covered >= frames {
+                break;
+            }
+            flags |= packet.flags & AUDCLNT_BUFFERFLAGS_DATA_DISCONTINUITY.0 as u32;
+            covered += packet.frames;
+        }
+        (
+            front.position + offset as u64,
+            front.qpc + offset as u64 * 10_000_000 / self.samplerate as u64,
+            flags & !(AUDCLNT_BUFFERFLAGS_SILENT.0 as u32),
+        )
+    }
+    fn next_packet(&self) -> u32 {
+        if self.available() >= self.packet {
+            self.packet
+        } else {
+            0
+        }
+    }
+}
+impl IAudioCaptureClient_Impl for RedirectRingbufAudioCaptureClient_Impl {
+    fn GetBuffer(
+        &self,
+        ppdata: *mut *mut u8,
+        pnumframestoread: *mut u32,
+        pdwflags: *mut u32,
+        pu64deviceposition: *mut u64,
+        pu64qpcposition: *mut u64,
+    ) -> WinResult<()> {
+        self.trim()?;
+        let frames = self.next_packet();
+        unsafe { *pnumframestoread = frames }
+        if frames == 0 {
+            trace_tagged!(self.tag, "GetBuffer called, buffer is empty");
+            // the implement shim returns `e.code()` as is, so apps still see a success code,
+            // pinned by `tests::empty_capture_buffer_is_a_success_code`
+            return Err(AUDCLNT_S_BUFFER_EMPTY.into());
+        }
+        let len = self.align.frames_to_bytes(frames as usize);
+        let cache = unsafe { &mut *self.cache.get() };
+        let chunk = unsafe { &mut *self.buffer.get() }
+            .read_chunk(len)
+            .map_err(|_| Error::from_hresult(AUDCLNT_E_BUFFER_ERROR))?;
+        let (first, second) = chunk.as_slices();
+        cache[..first.len()].copy_from_slice(first);
+        cache[first.len()..len].copy_from_slice(second);
+        let (position, qpc, mut flags) = self.position(frames);
+        if self.discontinuity.take() {
+            flags |= AUDCLNT_BUFFERFLAGS_DATA_DISCONTINUITY.0 as u32;
+        }
+        unsafe {
+            *ppdata = cache.as_mut_ptr();
+            *pdwflags = flags;
+            if let Some(p) = pu64deviceposition.as_mut() {
+                *p = position
+            }
+            if let Some(p) = pu64qpcposition.as_mut() {
+                *p = qpc
+            }
+        }
+        trace_tagged!(
+            self.tag,
+            "GetBuffer called, frames: {frames}, position: {position}"
+        );
+        Ok(())
+    }
+    fn ReleaseBuffer(&self, numframesread: u32) -> WinResult<()> {
+        trace_tagged!(self.tag, "ReleaseBuffer called, read: {numframesread}");
+        if numframesread == 0 {
+            return Ok(());
+        }
+        self.advance(numframesread)
+    }
+    fn GetNextPacketSize(&self) -> WinResult<u32> {
+        let frames = self.next_packet();
+        trace_tagged!(self.tag, "GetNextPacketSize called, frames: {frames}");
+        Ok(frames)
+    }
+}
+
 fn formatter(
     w: &mut dyn std::io::Write,
     _: &mut DeferredNow,
@@ -1670,34 +3033,200 @@ fn setup() -> LoggerHandle {
     }
     .start()
     .expect("unable to setup logger");
-    info!(
-        "Attempting to load config from working directory: {}",
-        std::env::current_dir().map_or_else(
-            |e| format!("unknown, err: {e}"),
-            |path| path.display().to_string()
-        )
-    );
-    match CONFIG.source {
-        ConfigSource::Success => info!("Config loaded!"),
-        ConfigSource::NoParse => {
-            warn!("Unable to parse config, using default values")
+    for path in &CONFIG.searched {
+        info!("Looking for config at: {}", path.display());
+    }
+    match &CONFIG.source {
+        ConfigSource::Success(path) => {
+            info!("Config loaded from {}", path.display());
+            for layer in CONFIG.layers.iter().filter(|layer| *layer != path) {
+                info!("Layered under it: {}", layer.display());
+            }
+            match (&CONFIG.profile, &CONFIG.process) {
+                (Some(profile), _) => info!("Using profile: {profile}"),
+                (None, Some(process)) => info!("No profile for {process}, using global config"),
+                (None, None) => warn!("Unable to get process name, using global config"),
+            }
+        }
+        ConfigSource::NoParse(e) => {
+            warn!("Unable to parse config, using default values, error: {e}")
         }
         ConfigSource::NoFile => {
-            info!("Config file not found, using default values")
+            info!("Config file not found, using default values");
+            if std::env::var_os(GENERATE_ENV).is_some() && !GENERATING.load(Ordering::Relaxed) {
+                write_default_config(Vec::new());
+            }
         }
     }
+    for EnvOverride { var, key, value } in &CONFIG.overrides {
+        info!("Overriding {key} = {value} from {var}")
+    }
+    for warning in &CONFIG.warnings {
+        warn!("{warning}")
+    }
+    for (key, value, origin) in CONFIG.effective_values() {
+        info!("{key} = {value} ({origin})")
+    }
     handle
 }
 
+static GENERATING: AtomicBool = AtomicBool::new(false);
+
+fn probe_endpoints() -> WinResult<Vec<ProbedEndpoint>> {
+    let enumerator = unsafe {
+        let mut ppv = std::ptr::null_mut();
+        CO_CREATE
+            .0
+            .call(
+                &MMDeviceEnumerator,
+                std::ptr::null_mut(),
+                CLSCTX_ALL,
+                &IMMDeviceEnumerator::IID,
+                &mut ppv,
+            )
+            .ok()?;
+        IMMDeviceEnumerator::from_raw(ppv)
+    };
+    let mut probed = Vec::new();
+    for (flow, dataflow) in [
+        (eRender, DeviceDataFlow::Playback),
+        (eCapture, DeviceDataFlow::Capture),
+    ] {
+        let default = unsafe { enumerator.GetDefaultAudioEndpoint(flow, eConsole) }
+            .map(|device| endpoint_names(&device))
+            .unwrap_or_default();
+        let collection = unsafe { enumerator.EnumAudioEndpoints(flow, DEVICE_STATE_ACTIVE)? };
+        let mut devices = (0..unsafe { collection.GetCount()? })
+            .filter_map(|i| unsafe { collection.Item(i) }.ok())
+            .map(|device| (endpoint_names(&device), device))
+            .collect::<Vec<_>>();
+        devices.sort_by_key(|(names, _)| names.first() != default.first());
+        for (names, device) in devices {
+            let Ok(client) = (unsafe { device.Activate::<IAudioClient3>(CLSCTX_ALL, None) }) else {
+                continue;
+            };
+            let mut periods = [0; 4];
+            let Ok(pformat) = (unsafe { client.GetMixFormat() }) else {
+                continue;
+            };
+            let samplerate = unsafe { *pformat }.nSamplesPerSec;
+            let hr = unsafe {
+                client.GetSharedModeEnginePeriod(
+                    pformat,
+                    &mut periods[0],
+                    &mut periods[1],
+                    &mut periods[2],
+                    &mut periods[3],
+                )
+            };
+            unsafe { CoTaskMemFree(Some(pformat.cast())) };
+            if hr.is_err() {
+                continue;
+            }
+            probed.push(ProbedEndpoint {
+                flow: dataflow,
+                name: names.into_iter().last().unwrap_or_default(),
+                samplerate,
+                fundamental: periods[1],
+                period: (periods[2], periods[3]),
+            });
+        }
+    }
+    Ok(probed)
+}
+
+fn write_default_config(probed: Vec<ProbedEndpoint>) {
+    let Some(path) = module_dir().map(|dir| dir.join(CONFIG_NAME)) else {
+        warn!("Unable to locate the module directory, config not generated");
+        return;
+    };
+    for endpoint in &probed {
+        info!(
+            "Probed {} endpoint {}: {}Hz, period {}..{}",
+            endpoint.flow, endpoint.name, endpoint.samplerate, endpoint.period.0, endpoint.period.1
+        );
+    }
+    let result = std::fs::File::create_new(&path).and_then(|mut file| {
+        std::io::Write::write_all(&mut file, generate_config(&probed).as_bytes())
+    });
+    match result {
+        Ok(()) => info!("Generated config at {}", path.display()),
+        Err(e) => warn!(
+            "Unable to generate config at {}, error: {e}",
+            path.display()
+        ),
+    }
+}
+
+#[unsafe(export_name = "generate_config")]
+extern "system" fn generate_config_entry(_: HWND, _: HINSTANCE, _: PCSTR, _: i32) {
+    let com = unsafe { CoInitializeEx(None, COINIT_MULTITHREADED) };
+    GENERATING.store(true, Ordering::Relaxed);
+    let handle = LOGGER_HANDLE.get_or_init(setup);
+    write_default_config(probe_endpoints().unwrap_or_else(|e| {
+        warn!("Unable to probe endpoints, generating without them, error: {e}");
+        Vec::new()
+    }));
+    handle.flush();
+    if com.is_ok() {
+        unsafe { CoUninitialize() };
+    }
+}
+
 #[unsafe(export_name = "proxy")]
 extern "C" fn proxy_dummy() {}
 
 #[unsafe(no_mangle)]
-unsafe extern "system" fn DllMain(_: HINSTANCE, reason: u32, _: *mut c_void) -> BOOL {
+unsafe extern "system" fn DllMain(hinstance: HINSTANCE, reason: u32, _: *mut c_void) -> BOOL {
     match reason {
-        1 => unsafe { CO_CREATE.0.enable().is_ok() && CO_CREATE.1.enable().is_ok() },
+        1 => unsafe {
+            MODULE.store(hinstance.0, Ordering::Relaxed);
+            CO_CREATE.0.enable().is_ok() && CO_CREATE.1.enable().is_ok()
+        },
         0 => unsafe { CO_CREATE.0.disable().is_ok() && CO_CREATE.1.disable().is_ok() },
         _ => true,
     }
     .into()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_capture_buffer_is_a_success_code() {
+        let (_, buffer) = RingBuffer::new(64);
+        let (_, packets) = RingBuffer::new(4);
+        let client: IAudioCaptureClient = RedirectRingbufAudioCaptureClient {
+            buffer: buffer.into(),
+            packets: packets.into(),
+            cache: vec![0u8; 64].into_boxed_slice().into(),
+            offset: 0.into(),
+            discontinuity: false.into(),
+            packet: 8,
+            len: 16,
+            overflow: RingOverflow::DropNewest,
+            samplerate: 48000,
+            align: AudioAlign::new(4),
+            counters: Default::default(),
+            tag: "capture".into(),
+        }
+        .into();
+        let (mut data, mut frames, mut flags) = (std::ptr::null_mut(), u32::MAX, 0);
+        let code = unsafe {
+            (client.vtable().GetBuffer)(
+                client.as_raw(),
+                &mut data,
+                &mut frames,
+                &mut flags,
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+            )
+        };
+        assert_eq!(code, AUDCLNT_S_BUFFER_EMPTY);
+        assert_eq!(frames, 0);
+        assert!(
+            unsafe { client.GetBuffer(&mut data, &mut frames, &mut flags, None, None) }.is_ok()
+        );
+    }
+}